@@ -0,0 +1,117 @@
+//! Commands typed into the Compose box.
+//!
+//! Input starting with a slash is a command rather than a message. To send a message starting with a slash, type it twice.
+
+use std::path::PathBuf;
+
+/// Usage of all commands, shown by `/help`.
+pub const HELP: &[&str] = &[
+    "/help                 show this help",
+    "/nick <name>          change the name your messages are shown with",
+    "/connect <host:port>  send further queries to another peer",
+    "/clear                clear the message pane, keeping the history",
+    "/save [path]          save the conversation as text",
+    "/me <action>          describe what you are doing",
+    "/send <path>          send a file",
+    "/quit                 leave the chat",
+];
+
+/// What a line typed into the Compose box asks for.
+#[derive(Debug, PartialEq)]
+pub enum Input {
+    /// Text to send to the peer
+    Message(String),
+    Command(Command),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Help,
+    Nick(String),
+    Connect {
+        host: String,
+        port: u16,
+    },
+    Clear,
+    /// Saves the conversation to the given file, or to one named after the current time
+    Save(Option<PathBuf>),
+    Me(String),
+    Send(PathBuf),
+    Quit,
+}
+
+/// Parses a line typed into the Compose box, describing what is wrong with malformed or unknown commands.
+pub fn parse(input: &str) -> Result<Input, String> {
+    let line = match input.strip_prefix('/') {
+        Some(line) if !line.starts_with('/') => line,
+        // a doubled slash escapes the first one
+        Some(message) => return Ok(Input::Message(message.to_string())),
+        None => return Ok(Input::Message(input.to_string())),
+    };
+
+    let (name, argument) = match line.split_once(' ') {
+        Some((name, argument)) => (name, argument.trim()),
+        None => (line, ""),
+    };
+    let command = match (name, argument) {
+        ("help", _) => Command::Help,
+        ("nick", "") => return Err("usage: /nick <name>".into()),
+        ("nick", nick) => Command::Nick(nick.to_string()),
+        ("connect", target) => {
+            let (host, port) = parse_target(target).ok_or("usage: /connect <host:port>")?;
+            Command::Connect { host, port }
+        }
+        ("clear", _) => Command::Clear,
+        ("save", "") => Command::Save(None),
+        ("save", path) => Command::Save(Some(PathBuf::from(path))),
+        ("me", "") => return Err("usage: /me <action>".into()),
+        ("me", action) => Command::Me(action.to_string()),
+        ("send", "") => return Err("usage: /send <path>".into()),
+        ("send", path) => Command::Send(PathBuf::from(path)),
+        ("quit", _) => Command::Quit,
+        (name, _) => return Err(format!("unknown command /{}, see /help", name)),
+    };
+    Ok(Input::Command(command))
+}
+
+/// Parses `host:port`, where IPv6 addresses are enclosed in brackets.
+fn parse_target(target: &str) -> Option<(String, u16)> {
+    let (host, port) = target.rsplit_once(':')?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(parse("hello"), Ok(Input::Message("hello".into())));
+        assert_eq!(parse("//hello"), Ok(Input::Message("/hello".into())));
+        assert_eq!(
+            parse("/me waves "),
+            Ok(Input::Command(Command::Me("waves".into())))
+        );
+        assert_eq!(parse("/save"), Ok(Input::Command(Command::Save(None))));
+        assert_eq!(
+            parse("/connect [::1]:5353"),
+            Ok(Input::Command(Command::Connect {
+                host: "::1".into(),
+                port: 5353
+            }))
+        );
+        assert!(parse("/connect example.org").is_err());
+        assert!(parse("/nick").is_err());
+        assert_eq!(
+            parse("/shrug"),
+            Err("unknown command /shrug, see /help".into())
+        );
+    }
+}
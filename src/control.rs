@@ -0,0 +1,351 @@
+//! A control API for other programs, like editor plugins or notification daemons.
+//!
+//! Clients connect to a Unix socket and exchange [JSON-RPC 2.0](https://www.jsonrpc.org/specification) messages with us, one per line. They share the conversation with the user interface (see `hub`). The methods are:
+//!
+//! - `send_message` with `text` and optionally `action`, sending a message and returning its `id`
+//! - `list_history` with an optional `limit`, returning the most recent messages
+//! - `subscribe`, after which every message received is sent as notification `message`, none being left out for clients reading slowly
+//! - `connection_status`, returning what is known about the connection to the peer
+
+use crate::headless;
+use crate::hub::{Event, Hub, Subscription};
+use crate::state::{Connection, MessageType};
+use crate::transport::ChatMessage;
+use chrono::SecondsFormat;
+use serde_json::{json, Value};
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+/// Serves the control API on a Unix socket at `path`, replacing a socket left behind by an earlier run.
+///
+/// Anything else at `path` is left alone. Only the owner may connect, as clients can chat on their behalf.
+pub async fn serve(path: &Path, hub: Arc<Hub>) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    let listener = bind_private(path)?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let hub = hub.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, hub).await {
+                eprintln!("[control] Dropping client: {}", e);
+            }
+        });
+    }
+}
+
+/// Binds a Unix socket at `path` only the owner may connect to.
+///
+/// The socket is created in a directory only the owner may enter and moved to `path` once its permissions are restricted, so it is never reachable with the looser ones of the umask.
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a socket path"))?;
+    let dir = path.with_file_name(format!(
+        ".{}-{:08x}",
+        name.to_string_lossy(),
+        rand::random::<u32>()
+    ));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let staged = dir.join("socket");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    fs::remove_dir_all(&dir)?;
+    bound
+}
+
+/// Answers the requests of a client until it disconnects.
+async fn handle_client(stream: UnixStream, hub: Arc<Hub>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    // events of the hub, once subscribed
+    let mut events: Option<Subscription> = None;
+
+    loop {
+        let reply = tokio::select! {
+            line = lines.next_line() => match line? {
+                Some(line) if line.trim().is_empty() => continue,
+                Some(line) => match handle_request(&line, &hub, &mut events) {
+                    Some(reply) => reply,
+                    None => continue,
+                },
+                None => return Ok(()),
+            },
+            event = next_event(&mut events) => match event {
+                Some(Event::Received(msg)) => json!({
+                    "jsonrpc": "2.0",
+                    "method": "message",
+                    "params": message_json(&msg, &MessageType::Received),
+                }),
+                Some(Event::Changed) => continue,
+                None => return Ok(()),
+            },
+        };
+
+        let mut line = reply.to_string();
+        line.push('\n');
+        writer.write_all(line.as_bytes()).await?;
+    }
+}
+
+/// Waits for the next event if subscribed, and forever otherwise.
+async fn next_event(events: &mut Option<Subscription>) -> Option<Event> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Handles a single request, returning the response unless the request was a notification.
+fn handle_request(line: &str, hub: &Hub, events: &mut Option<Subscription>) -> Option<Value> {
+    let request: Value = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => return Some(error(Value::Null, PARSE_ERROR, &e.to_string())),
+    };
+    let id = request.get("id").cloned();
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) if request["jsonrpc"] == "2.0" => method,
+        _ => {
+            let id = id.unwrap_or(Value::Null);
+            return Some(error(id, INVALID_REQUEST, "not a JSON-RPC 2.0 request"));
+        }
+    };
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+    let result = match method {
+        "send_message" => send_message(hub, &params),
+        "list_history" => list_history(hub, &params),
+        "subscribe" => {
+            events.get_or_insert_with(|| hub.subscribe());
+            Ok(Value::Bool(true))
+        }
+        "connection_status" => Ok(connection_json(&hub.state().connection)),
+        _ => Err((METHOD_NOT_FOUND, format!("unknown method {}", method))),
+    };
+
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => error(id, code, &message),
+    })
+}
+
+fn send_message(hub: &Hub, params: &Value) -> Result<Value, (i64, String)> {
+    let text = params["text"]
+        .as_str()
+        .ok_or((INVALID_PARAMS, "text is required".to_string()))?;
+    let mut msg = ChatMessage::new(text.to_string());
+    msg.action = params["action"].as_bool().unwrap_or(false);
+
+    let msg = hub.state().send(msg);
+    let id = msg.id;
+    hub.send(msg)
+        .map_err(|e| (INTERNAL_ERROR, format!("could not send: {}", e)))?;
+    Ok(json!({ "id": id }))
+}
+
+fn list_history(hub: &Hub, params: &Value) -> Result<Value, (i64, String)> {
+    let limit = match &params["limit"] {
+        Value::Null => usize::MAX,
+        limit => limit
+            .as_u64()
+            .ok_or((INVALID_PARAMS, "limit must be a number".to_string()))?
+            as usize,
+    };
+    let state = hub.state();
+    let messages: Vec<Value> = state
+        .messages
+        .iter()
+        .filter(|(_, ty)| *ty != MessageType::System)
+        .map(|(msg, ty)| message_json(msg, ty))
+        .collect();
+    let messages = messages[messages.len().saturating_sub(limit)..].to_vec();
+    Ok(Value::Array(messages))
+}
+
+/// A message as JSON, like written by `--headless --output json` along with its id and status.
+fn message_json(msg: &ChatMessage, ty: &MessageType) -> Value {
+    let mut value = headless::message_json(msg);
+    let status = match ty {
+        MessageType::Sent => "sent",
+        MessageType::Queued => "queued",
+        MessageType::Delivered => "delivered",
+        MessageType::Failed => "failed",
+        MessageType::Received => "received",
+        MessageType::System => "system",
+    };
+    value["id"] = json!(msg.id);
+    value["status"] = json!(status);
+    value
+}
+
+fn connection_json(connection: &Connection) -> Value {
+    json!({
+        "connected": connection.last_contact.is_some() && connection.error.is_none(),
+        "last_contact": connection
+            .last_contact
+            .map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, false)),
+        "latency_ms": connection.latency.map(|latency| latency.as_millis() as u64),
+        "error": connection.error,
+        "queue": connection.queue,
+        "bytes_sent": connection.bytes_sent,
+        "bytes_received": connection.bytes_received,
+    })
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::State;
+    use crate::transport::Update;
+    use std::env;
+    use std::path::PathBuf;
+    use tokio::io::Lines;
+    use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::sync::mpsc;
+
+    /// A connected client of the control API.
+    struct Client {
+        writer: OwnedWriteHalf,
+        lines: Lines<BufReader<OwnedReadHalf>>,
+    }
+
+    impl Client {
+        async fn connect(path: &Path) -> Self {
+            // the server may not be listening yet
+            let stream = loop {
+                match UnixStream::connect(path).await {
+                    Ok(stream) => break stream,
+                    Err(_) => tokio::task::yield_now().await,
+                }
+            };
+            let (reader, writer) = stream.into_split();
+            Self {
+                writer,
+                lines: BufReader::new(reader).lines(),
+            }
+        }
+
+        async fn call(&mut self, request: Value) -> Value {
+            let line = format!("{}\n", request);
+            self.writer.write_all(line.as_bytes()).await.unwrap();
+            self.receive().await
+        }
+
+        async fn receive(&mut self) -> Value {
+            let line = self.lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_share_the_conversation() {
+        let (outgoing, mut sent) = mpsc::unbounded_channel();
+        let hub = Hub::new(State::new(), outgoing, PathBuf::from("downloads"));
+        let (updates, recv) = mpsc::unbounded_channel();
+        tokio::spawn(hub.clone().dispatch(recv));
+        let path = env::temp_dir().join(format!("kakure-{}.sock", rand::random::<u32>()));
+        tokio::spawn({
+            let (path, hub) = (path.clone(), hub.clone());
+            async move { serve(&path, hub).await }
+        });
+        let mut client = Client::connect(&path).await;
+
+        let reply = client
+            .call(json!({"jsonrpc": "2.0", "id": 1, "method": "send_message", "params": {"text": "hello"}}))
+            .await;
+        let msg = sent.recv().await.unwrap();
+        assert_eq!(reply["result"]["id"], msg.id);
+        assert_eq!(hub.state().messages[0].1, MessageType::Queued);
+
+        let reply = client
+            .call(json!({"jsonrpc": "2.0", "id": 2, "method": "subscribe"}))
+            .await;
+        assert_eq!(reply["result"], true);
+        updates
+            .send(Update::Message(ChatMessage::new("hi".into())))
+            .unwrap();
+        let notification = client.receive().await;
+        assert_eq!(notification["method"], "message");
+        assert_eq!(notification["params"]["text"], "hi");
+
+        let reply = client
+            .call(json!({"jsonrpc": "2.0", "id": 3, "method": "list_history", "params": {"limit": 1}}))
+            .await;
+        assert_eq!(reply["result"].as_array().unwrap().len(), 1);
+        assert_eq!(reply["result"][0]["status"], "received");
+
+        let reply = client
+            .call(json!({"jsonrpc": "2.0", "id": 4, "method": "connection_status"}))
+            .await;
+        assert_eq!(reply["result"]["connected"], false);
+        let reply = client
+            .call(json!({"jsonrpc": "2.0", "id": 5, "method": "shout"}))
+            .await;
+        assert_eq!(reply["error"]["code"], METHOD_NOT_FOUND);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_sockets_are_replaced() {
+        let (outgoing, _sent) = mpsc::unbounded_channel();
+        let hub = Hub::new(State::new(), outgoing, PathBuf::from("downloads"));
+        let path = env::temp_dir().join(format!("kakure-{}.sock", rand::random::<u32>()));
+
+        fs::write(&path, "precious").unwrap();
+        assert!(serve(&path, hub.clone()).await.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "precious");
+        fs::remove_file(&path).unwrap();
+
+        // a socket left behind is replaced by one only the owner may use
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        tokio::spawn({
+            let path = path.clone();
+            async move { serve(&path, hub).await }
+        });
+        Client::connect(&path).await;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // the directory it was created in is gone
+        let staged = format!(".{}-", path.file_name().unwrap().to_string_lossy());
+        assert!(!fs::read_dir(env::temp_dir()).unwrap().any(|entry| entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with(&staged)));
+        fs::remove_file(path).unwrap();
+    }
+}
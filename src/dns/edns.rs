@@ -0,0 +1,118 @@
+//! Extension Mechanisms for DNS (EDNS(0)) as defined in [RFC 6891](https://tools.ietf.org/html/rfc6891).
+//!
+//! EDNS is signalled by an `OPT` pseudo-record in the additional section of a message. It overloads the class field of the record with the UDP payload size the sender is able to receive and the TTL field with the extended RCODE, the EDNS version and some flags.
+
+use super::messages::DNSAnswer;
+use super::types::{EdnsOption, RecordClass, RecordData, RecordType};
+
+/// The payload size every DNS implementation has to be able to handle (RFC 1035, 4.2.1).
+pub const MIN_UDP_PAYLOAD_SIZE: u16 = 512;
+
+/// The EDNS version implemented here.
+pub const EDNS_VERSION: u8 = 0;
+
+/// The contents of an `OPT` pseudo-record.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Edns {
+    /// The largest UDP payload the sender of the record is able to reassemble
+    pub udp_payload_size: u16,
+    /// The upper 8 bits of the 12 bit response code
+    pub extended_rcode: u8,
+    /// The EDNS version the sender implements
+    pub version: u8,
+    /// Whether the sender is able to handle DNSSEC records
+    pub dnssec_ok: bool,
+    /// Options attached to the record
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    pub fn new(udp_payload_size: u16) -> Self {
+        Self {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: EDNS_VERSION,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    /// Extracts the EDNS information from an `OPT` record. Returns `None` for any other record type.
+    pub fn from_record(record: &DNSAnswer) -> Option<Self> {
+        match (&record.rclass, &record.record) {
+            (RecordClass::OPT(udp_payload_size), RecordData::Opt(options)) => Some(Self {
+                udp_payload_size: *udp_payload_size,
+                extended_rcode: (record.ttl >> 24) as u8,
+                version: (record.ttl >> 16) as u8,
+                dnssec_ok: record.ttl & 0x8000 == 0x8000,
+                options: options.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Converts the EDNS information into an `OPT` record to be placed in the additional section.
+    pub fn into_record(self) -> DNSAnswer {
+        let ttl = (u32::from(self.extended_rcode) << 24)
+            | (u32::from(self.version) << 16)
+            | (u32::from(self.dnssec_ok) << 15);
+        let data_length = self
+            .options
+            .iter()
+            .fold(0, |acc, option| acc + 4 + option.data.len()) as u16;
+
+        DNSAnswer {
+            name: String::new(),
+            rtype: RecordType::OPT,
+            rclass: RecordClass::OPT(self.udp_payload_size),
+            ttl,
+            data_length,
+            record: RecordData::Opt(self.options),
+        }
+    }
+
+    /// Determines the payload size a reply may use, given the EDNS information of a request and the payload size we are willing to send.
+    ///
+    /// Requests without EDNS are limited to the classic 512 bytes, and advertised sizes below that value are treated as 512 (RFC 6891, 6.2.5).
+    pub fn negotiate(request: Option<&Edns>, own_payload_size: u16) -> u16 {
+        match request {
+            Some(edns) => edns
+                .udp_payload_size
+                .max(MIN_UDP_PAYLOAD_SIZE)
+                .min(own_payload_size.max(MIN_UDP_PAYLOAD_SIZE)),
+            None => MIN_UDP_PAYLOAD_SIZE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_round_trip() {
+        let edns = Edns {
+            udp_payload_size: 4096,
+            extended_rcode: 1,
+            version: 0,
+            dnssec_ok: true,
+            options: vec![EdnsOption {
+                code: 10,
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            }],
+        };
+
+        let record = edns.clone().into_record();
+        assert_eq!(record.ttl, 0x0100_8000);
+        assert_eq!(record.data_length, 12);
+        assert_eq!(Edns::from_record(&record), Some(edns));
+    }
+
+    #[test]
+    fn payload_size_negotiation() {
+        assert_eq!(Edns::negotiate(None, 4096), 512);
+        assert_eq!(Edns::negotiate(Some(&Edns::new(1232)), 4096), 1232);
+        assert_eq!(Edns::negotiate(Some(&Edns::new(4096)), 1232), 1232);
+        assert_eq!(Edns::negotiate(Some(&Edns::new(100)), 4096), 512);
+    }
+}
@@ -0,0 +1,63 @@
+//! Errors that may occur while reading DNS messages from the wire.
+
+use std::fmt;
+
+/// An error encountered while parsing a DNS message.
+///
+/// Carries the kind of problem as well as the byte offset into the message at which it was detected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParseError {
+    /// What went wrong
+    pub kind: ParseErrorKind,
+    /// Offset of the offending byte, counted from the start of the message
+    pub offset: usize,
+}
+
+/// The different kinds of malformed input the parser recognizes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParseErrorKind {
+    /// The message is shorter than the 12 byte header
+    TruncatedHeader,
+    /// The message ended in the middle of a section
+    Truncated,
+    /// A label or domain name exceeds the limits of RFC 1035 or the message itself
+    LabelOverflow,
+    /// A compression pointer references an invalid location
+    BadPointer,
+    /// A record type this implementation does not know
+    UnknownType(u16),
+    /// A record class this implementation does not know
+    UnknownClass(u16),
+    /// The `RDLENGTH` field does not match the data contained in the record
+    RdataLengthMismatch { expected: u16, actual: usize },
+    /// Bytes left over after all sections announced in the header were read
+    TrailingBytes(usize),
+}
+
+impl ParseError {
+    pub fn new(kind: ParseErrorKind, offset: usize) -> Self {
+        Self { kind, offset }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ParseErrorKind::TruncatedHeader => write!(f, "message too short for a DNS header")?,
+            ParseErrorKind::Truncated => write!(f, "unexpected end of message")?,
+            ParseErrorKind::LabelOverflow => write!(f, "label exceeds permitted length")?,
+            ParseErrorKind::BadPointer => write!(f, "invalid compression pointer")?,
+            ParseErrorKind::UnknownType(t) => write!(f, "unknown record type {}", t)?,
+            ParseErrorKind::UnknownClass(c) => write!(f, "unknown record class {}", c)?,
+            ParseErrorKind::RdataLengthMismatch { expected, actual } => write!(
+                f,
+                "record data announces {} bytes but contains {}",
+                expected, actual
+            )?,
+            ParseErrorKind::TrailingBytes(n) => write!(f, "{} trailing bytes after message", n)?,
+        }
+        write!(f, " (at byte {})", self.offset)
+    }
+}
+
+impl std::error::Error for ParseError {}
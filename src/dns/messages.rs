@@ -2,12 +2,15 @@
 //!
 //! This module implements data structures and methods for interacting with DNS messages, as far as necessary for the purpose of this application.
 
+use super::edns::Edns;
+use super::error::{ParseError, ParseErrorKind};
 use super::types::*;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 
 /// A single DNS message.
 ///
-/// This data structure is a simplified version of a DNS message, ignoring the `Authority` and `Additional` sections for resource records.
+/// The answer, authority and additional sections all consist of resource records and therefore share the `DNSAnswer` type.
 #[derive(Clone, Debug, PartialEq)]
 pub struct DNSMessage {
     /// The DNS Header
@@ -16,6 +19,10 @@ pub struct DNSMessage {
     pub questions: Vec<DNSQuestion>,
     /// Answer section of the DNS message
     pub answers: Option<Vec<DNSAnswer>>,
+    /// Authority section of the DNS message, pointing toward authoritative name servers
+    pub authorities: Option<Vec<DNSAnswer>>,
+    /// Additional section of the DNS message, holding records related to the query
+    pub additionals: Option<Vec<DNSAnswer>>,
 }
 
 impl DNSMessage {
@@ -29,30 +36,84 @@ impl DNSMessage {
             header,
             questions,
             answers: None,
+            authorities: None,
+            additionals: None,
         }
     }
 
+    /// Appends an answer to the first question and turns the message into a response.
     pub fn add_answer(&mut self, answer: RecordData) {
-        let reply_block = vec![DNSAnswer::create_from_question(&self.questions[0], answer)];
-        let reply_count = reply_block.len() as u16;
-        // TODO(feliix42): We could take multiple answers here and package them together nicely
-        self.answers = Some(reply_block);
+        let answer = DNSAnswer::create_from_question(&self.questions[0], answer);
+        let answers = self.answers.get_or_insert_with(Vec::new);
+        answers.push(answer);
+        let answer_count = answers.len() as u16;
 
+        self.set_response();
+        self.header.answer_count = answer_count;
+    }
+
+    /// Turns a request into a response by adjusting the header fields.
+    pub fn set_response(&mut self) {
         // TODO(feliix42): carefully check the fields on this?
-        // adjust header fields
         self.header.is_response = true;
         self.header.recursion_available = self.header.recursion_desired;
         self.header.response_code = 0;
-        self.header.answer_count = reply_count;
+    }
+
+    /// Returns the EDNS information of the message, if it carries an `OPT` record.
+    pub fn edns(&self) -> Option<Edns> {
+        self.additionals
+            .iter()
+            .flatten()
+            .find_map(Edns::from_record)
+    }
+
+    /// Attaches an `OPT` record to the additional section, replacing any existing one.
+    pub fn set_edns(&mut self, edns: Edns) {
+        let additionals = self.additionals.get_or_insert_with(Vec::new);
+        additionals.retain(|record| record.rtype != RecordType::OPT);
+        additionals.push(edns.into_record());
+        self.header.ar_count = additionals.len() as u16;
+    }
+
+    /// Builder-style variant of `set_edns`, e.g. for use with `new_request`.
+    pub fn with_edns(mut self, edns: Edns) -> Self {
+        self.set_edns(edns);
+        self
+    }
+
+    /// The full 12 bit response code, combining the header bits with the extended RCODE of an `OPT` record.
+    pub fn response_code(&self) -> u16 {
+        let extended = self.edns().map_or(0, |edns| edns.extended_rcode);
+        (u16::from(extended) << 4) | u16::from(self.header.response_code)
+    }
+
+    /// Whether this message is a reply to `request`, i.e. carries the same ID and question.
+    ///
+    /// Replies failing this check may have been spoofed and should be discarded.
+    pub fn is_reply_to(&self, request: &DNSMessage) -> bool {
+        self.header.is_response
+            && self.header.id == request.header.id
+            && self.questions.len() == request.questions.len()
+            && self
+                .questions
+                .iter()
+                .zip(request.questions.iter())
+                .all(|(ours, theirs)| {
+                    // resolvers may alter the case of the query name
+                    ours.name.eq_ignore_ascii_case(&theirs.name)
+                        && ours.qtype == theirs.qtype
+                        && ours.qclass == theirs.qclass
+                })
     }
 }
 
-impl Into<Vec<u8>> for DNSMessage {
-    fn into(self) -> Vec<u8> {
+impl From<DNSMessage> for Vec<u8> {
+    fn from(message: DNSMessage) -> Self {
         let mut msg = Vec::with_capacity(12);
 
         // header processing
-        let header = self.header;
+        let header = message.header;
         msg.extend_from_slice(&header.id.to_be_bytes());
 
         let mut byte_3: u8 = u8::from(header.is_response) << 7;
@@ -71,52 +132,23 @@ impl Into<Vec<u8>> for DNSMessage {
         msg.extend_from_slice(&header.ns_record_count.to_be_bytes());
         msg.extend_from_slice(&header.ar_count.to_be_bytes());
 
+        // offsets of all names and name suffixes written so far, used for compression
+        let mut names = HashMap::new();
+
         // question section processing
-        let questions = self.questions;
+        let questions = message.questions;
         for question in questions {
-            for s in question.name.split('.') {
-                let bytes = s.as_bytes();
-                msg.push(u8::try_from(bytes.len()).unwrap());
-                msg.extend_from_slice(bytes);
-            }
-            msg.push(0);
+            write_domain_name(&mut msg, &question.name, &mut names);
 
             msg.extend_from_slice(&u16::from(question.qtype).to_be_bytes());
             msg.extend_from_slice(&u16::from(question.qclass).to_be_bytes());
         }
 
-        // answer section processing
-        if let Some(answers) = self.answers {
-            for answer in answers {
-                for s in answer.name.split('.') {
-                    let bytes = s.as_bytes();
-                    msg.push(u8::try_from(bytes.len()).unwrap());
-                    msg.extend_from_slice(bytes);
-                }
-                msg.push(0);
-
-                msg.extend_from_slice(&u16::from(answer.rtype).to_be_bytes());
-                msg.extend_from_slice(&u16::from(answer.rclass).to_be_bytes());
-                msg.extend_from_slice(&answer.ttl.to_be_bytes());
-                msg.extend_from_slice(&answer.data_length.to_be_bytes());
-
-                match answer.record {
-                    RecordData::Txt(contents) => {
-                        for content in contents {
-                            let bytes = content.as_bytes();
-                            if bytes.len() > u8::MAX as usize {
-                                // truncate sequence
-                                msg.push(u8::MAX);
-                                msg.extend_from_slice(&bytes[..u16::MAX as usize]);
-                            } else {
-                                msg.push(bytes.len() as u8);
-                                msg.extend_from_slice(bytes);
-                            }
-                        }
-                    }
-                    // TODO(feliix42): This will probably produce a malformed DNS packet but I don't care right now
-                    RecordData::Unsupported => (),
-                }
+        // resource record sections, in the order mandated by RFC 1035, 4.1
+        let sections = vec![message.answers, message.authorities, message.additionals];
+        for records in sections.into_iter().flatten() {
+            for record in records {
+                record.write(&mut msg, &mut names);
             }
         }
 
@@ -124,35 +156,38 @@ impl Into<Vec<u8>> for DNSMessage {
     }
 }
 
-impl From<&[u8]> for DNSMessage {
-    fn from(msg: &[u8]) -> Self {
-        let header = DNSHeader::from(&msg[0..12]);
+impl TryFrom<&[u8]> for DNSMessage {
+    type Error = ParseError;
+
+    fn try_from(msg: &[u8]) -> Result<Self, Self::Error> {
+        let header = DNSHeader::try_from(msg)?;
 
         let mut pos = 12;
         let mut questions = Vec::with_capacity(header.question_count as usize);
         for _ in 0..header.question_count {
-            let (question, new_pos) = DNSQuestion::parse(msg, pos);
+            let (question, new_pos) = DNSQuestion::parse(msg, pos)?;
             questions.push(question);
             pos = new_pos;
         }
 
-        let answers = if header.answer_count > 0 {
-            let mut answers = Vec::with_capacity(header.answer_count as usize);
-            for _ in 0..header.answer_count {
-                let (answer, new_pos) = DNSAnswer::parse(msg, pos);
-                answers.push(answer);
-                pos = new_pos;
-            }
-            Some(answers)
-        } else {
-            None
-        };
+        let answers = DNSAnswer::parse_section(msg, &mut pos, header.answer_count)?;
+        let authorities = DNSAnswer::parse_section(msg, &mut pos, header.ns_record_count)?;
+        let additionals = DNSAnswer::parse_section(msg, &mut pos, header.ar_count)?;
 
-        DNSMessage {
+        if pos != msg.len() {
+            return Err(ParseError::new(
+                ParseErrorKind::TrailingBytes(msg.len() - pos),
+                pos,
+            ));
+        }
+
+        Ok(DNSMessage {
             header,
             questions,
             answers,
-        }
+            authorities,
+            additionals,
+        })
     }
 }
 
@@ -195,9 +230,15 @@ impl DNSHeader {
     }
 }
 
-impl From<&[u8]> for DNSHeader {
-    fn from(msg: &[u8]) -> Self {
-        Self {
+impl TryFrom<&[u8]> for DNSHeader {
+    type Error = ParseError;
+
+    fn try_from(msg: &[u8]) -> Result<Self, Self::Error> {
+        if msg.len() < 12 {
+            return Err(ParseError::new(ParseErrorKind::TruncatedHeader, msg.len()));
+        }
+
+        Ok(Self {
             id: u16::from_be_bytes([msg[0], msg[1]]),
             is_response: (msg[2] & 128u8) == 128,
             // bit mask for opcode: 01111000
@@ -211,7 +252,7 @@ impl From<&[u8]> for DNSHeader {
             answer_count: u16::from_be_bytes([msg[6], msg[7]]),
             ns_record_count: u16::from_be_bytes([msg[8], msg[9]]),
             ar_count: u16::from_be_bytes([msg[10], msg[11]]),
-        }
+        })
     }
 }
 
@@ -227,11 +268,13 @@ pub struct DNSQuestion {
 }
 
 impl DNSQuestion {
-    fn parse(msg: &[u8], mut pos: usize) -> (DNSQuestion, usize) {
-        let domain = parse_domain_name(msg, &mut pos);
+    fn parse(msg: &[u8], mut pos: usize) -> Result<(DNSQuestion, usize), ParseError> {
+        let domain = parse_domain_name(msg, &mut pos)?;
 
-        let qtype = RecordType::from(u16::from_be_bytes([msg[pos], msg[pos + 1]]));
-        let qclass = RecordClass::from(u16::from_be_bytes([msg[pos + 2], msg[pos + 3]]));
+        let qtype =
+            RecordType::try_from(read_u16(msg, pos)?).map_err(|kind| ParseError::new(kind, pos))?;
+        let qclass = RecordClass::try_from(read_u16(msg, pos + 2)?)
+            .map_err(|kind| ParseError::new(kind, pos + 2))?;
         pos += 4;
 
         let question = DNSQuestion {
@@ -240,7 +283,7 @@ impl DNSQuestion {
             qclass,
         };
 
-        (question, pos)
+        Ok((question, pos))
     }
 }
 
@@ -254,7 +297,7 @@ impl DNSQuestion {
     }
 }
 
-/// A single reply to a DNS query.
+/// A single resource record, as found in the answer, authority and additional sections.
 #[derive(Clone, Debug, PartialEq)]
 pub struct DNSAnswer {
     /// Domain name in question.
@@ -272,31 +315,81 @@ pub struct DNSAnswer {
 }
 
 impl DNSAnswer {
-    fn parse(msg: &[u8], mut pos: usize) -> (DNSAnswer, usize) {
-        let domain = parse_domain_name(msg, &mut pos);
+    fn parse(msg: &[u8], mut pos: usize) -> Result<(DNSAnswer, usize), ParseError> {
+        let domain = parse_domain_name(msg, &mut pos)?;
 
-        let rtype = RecordType::from(u16::from_be_bytes([msg[pos], msg[pos + 1]]));
-        let rclass = RecordClass::from(u16::from_be_bytes([msg[pos + 2], msg[pos + 3]]));
-        let ttl = u32::from_be_bytes([msg[pos + 4], msg[pos + 5], msg[pos + 6], msg[pos + 7]]);
-        let data_length = u16::from_be_bytes([msg[pos + 8], msg[pos + 9]]);
+        // resolvers add records of their own, e.g. signatures, which must not spoil the whole message
+        let raw_type = read_u16(msg, pos)?;
+        let rtype = RecordType::try_from(raw_type).unwrap_or(RecordType::Unknown(raw_type));
+        let raw_class = read_u16(msg, pos + 2)?;
+        let rclass = if rtype == RecordType::OPT {
+            RecordClass::OPT(raw_class)
+        } else {
+            RecordClass::try_from(raw_class).unwrap_or(RecordClass::Unknown(raw_class))
+        };
+        let ttl = read_u32(msg, pos + 4)?;
+        let data_length = read_u16(msg, pos + 8)?;
         pos += 10;
 
+        let data = msg
+            .get(pos..pos + data_length as usize)
+            .ok_or_else(|| ParseError::new(ParseErrorKind::Truncated, pos))?;
+
         // parse the record data
         let record = match rtype {
             RecordType::TXT => {
                 let mut contents = Vec::new();
                 let mut total_len = 0;
-                while total_len < data_length as usize {
-                    let len = msg[pos] as usize;
-                    pos += 1;
-                    contents.push(String::from_utf8_lossy(&msg[pos..pos + len]).to_string());
+                while total_len < data.len() {
+                    let len = data[total_len] as usize;
+                    let content =
+                        data.get(total_len + 1..total_len + 1 + len)
+                            .ok_or_else(|| {
+                                ParseError::new(
+                                    ParseErrorKind::RdataLengthMismatch {
+                                        expected: data_length,
+                                        actual: total_len + 1 + len,
+                                    },
+                                    pos + total_len,
+                                )
+                            })?;
+                    contents.push(content.to_vec());
                     total_len += 1 + len;
-                    pos += len;
                 }
                 RecordData::Txt(contents)
             }
-            _ => RecordData::Unsupported,
+            RecordType::OPT => {
+                let mut options = Vec::new();
+                let mut total_len = 0;
+                while total_len < data.len() {
+                    let mismatch = |actual| {
+                        ParseError::new(
+                            ParseErrorKind::RdataLengthMismatch {
+                                expected: data_length,
+                                actual,
+                            },
+                            pos + total_len,
+                        )
+                    };
+                    let head = data
+                        .get(total_len..total_len + 4)
+                        .ok_or_else(|| mismatch(total_len + 4))?;
+                    let code = u16::from_be_bytes([head[0], head[1]]);
+                    let len = u16::from_be_bytes([head[2], head[3]]) as usize;
+                    let option_data = data
+                        .get(total_len + 4..total_len + 4 + len)
+                        .ok_or_else(|| mismatch(total_len + 4 + len))?;
+                    options.push(EdnsOption {
+                        code,
+                        data: option_data.to_vec(),
+                    });
+                    total_len += 4 + len;
+                }
+                RecordData::Opt(options)
+            }
+            _ => RecordData::Unsupported(data.to_vec()),
         };
+        pos += data.len();
 
         let answer = DNSAnswer {
             name: domain,
@@ -307,15 +400,72 @@ impl DNSAnswer {
             record,
         };
 
-        (answer, pos)
+        Ok((answer, pos))
+    }
+
+    /// Parses `count` consecutive resource records, returning `None` for an empty section.
+    fn parse_section(
+        msg: &[u8],
+        pos: &mut usize,
+        count: u16,
+    ) -> Result<Option<Vec<DNSAnswer>>, ParseError> {
+        if count == 0 {
+            return Ok(None);
+        }
+
+        let mut records = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let (record, new_pos) = DNSAnswer::parse(msg, *pos)?;
+            records.push(record);
+            *pos = new_pos;
+        }
+
+        Ok(Some(records))
+    }
+
+    /// Appends the wire format of this record to `msg`, compressing the owner name using `names`.
+    fn write(self, msg: &mut Vec<u8>, names: &mut HashMap<String, usize>) {
+        write_domain_name(msg, &self.name, names);
+
+        msg.extend_from_slice(&u16::from(self.rtype).to_be_bytes());
+        msg.extend_from_slice(&u16::from(self.rclass).to_be_bytes());
+        msg.extend_from_slice(&self.ttl.to_be_bytes());
+        msg.extend_from_slice(&self.data_length.to_be_bytes());
+
+        match self.record {
+            RecordData::Txt(contents) => {
+                for content in contents {
+                    let bytes = content.as_slice();
+                    if bytes.len() > u8::MAX as usize {
+                        // truncate sequence
+                        msg.push(u8::MAX);
+                        msg.extend_from_slice(&bytes[..u8::MAX as usize]);
+                    } else {
+                        msg.push(bytes.len() as u8);
+                        msg.extend_from_slice(bytes);
+                    }
+                }
+            }
+            RecordData::Opt(options) => {
+                for option in options {
+                    msg.extend_from_slice(&option.code.to_be_bytes());
+                    msg.extend_from_slice(&(option.data.len() as u16).to_be_bytes());
+                    msg.extend_from_slice(&option.data);
+                }
+            }
+            RecordData::Unsupported(data) => msg.extend_from_slice(&data),
+        }
     }
 
     fn create_from_question(question: &DNSQuestion, data: RecordData) -> Self {
         let data_length = match &data {
-            RecordData::Txt(content) => {
-                content.iter().fold(0, |acc, s| acc + s.as_bytes().len()) as u16
-            }
-            RecordData::Unsupported => panic!("Unsupported RecordData type!"),
+            // every character-string is preceded by its length
+            RecordData::Txt(content) => content.iter().fold(0, |acc, s| acc + 1 + s.len()) as u16,
+            RecordData::Opt(options) => options
+                .iter()
+                .fold(0, |acc, option| acc + 4 + option.data.len())
+                as u16,
+            RecordData::Unsupported(data) => data.len() as u16,
         };
         Self {
             name: question.name.clone(),
@@ -328,52 +478,175 @@ impl DNSAnswer {
     }
 }
 
-fn parse_domain_name(msg: &[u8], pos: &mut usize) -> String {
-    // check for compression, which is indicated by leading `11` in the first octet
-    let is_backlink = msg[*pos] & 192u8 == 192;
+/// Longest label allowed by RFC 1035, 2.3.4.
+pub const MAX_LABEL_LENGTH: usize = 63;
 
+/// Longest name allowed by RFC 1035, 2.3.4, in its wire format including the length octets and the terminating zero octet.
+const MAX_WIRE_LENGTH: usize = 255;
+
+/// Checks that `name` can be written to the wire as it is, i.e. that it consists of non-empty labels of at most 63 bytes and takes at most 255 bytes on the wire. The empty name is the root domain.
+pub fn check_domain_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Ok(());
+    }
+    for label in name.split('.') {
+        if label.is_empty() {
+            return Err(format!("{} contains an empty label", name));
+        }
+        if label.len() > MAX_LABEL_LENGTH {
+            return Err(format!(
+                "label {} exceeds {} bytes",
+                label, MAX_LABEL_LENGTH
+            ));
+        }
+    }
+    // every label takes an additional length octet, the first one replacing a dot
+    if name.len() + 2 > MAX_WIRE_LENGTH {
+        return Err(format!("{} exceeds {} bytes", name, MAX_WIRE_LENGTH - 2));
+    }
+    Ok(())
+}
+
+/// Writes `name` to `msg`, compressing it as described in RFC 1035, 4.1.4.
+///
+/// `names` maps every name suffix that has been written to the message so far to its offset. If a suffix of `name` is already present, it is replaced by a pointer to the earlier occurrence. Newly written suffixes are added to the table.
+///
+/// Names are expected to pass `check_domain_name`. Others are still written as a valid name: empty labels are skipped, longer labels are cut off at 63 bytes and labels exceeding the length of a name are left out.
+fn write_domain_name(msg: &mut Vec<u8>, name: &str, names: &mut HashMap<String, usize>) {
+    // the root domain consists of the terminating zero octet only
+    if !name.is_empty() {
+        let labels: Vec<&str> = name.split('.').collect();
+        let mut wire_length = 1;
+        for i in 0..labels.len() {
+            let suffix = labels[i..].join(".");
+            if let Some(&offset) = names.get(&suffix) {
+                msg.extend_from_slice(&(0xC000 | offset as u16).to_be_bytes());
+                return;
+            }
+
+            let bytes = labels[i].as_bytes();
+            let bytes = &bytes[..bytes.len().min(MAX_LABEL_LENGTH)];
+            if bytes.is_empty() {
+                continue;
+            }
+            wire_length += bytes.len() + 1;
+            if wire_length > MAX_WIRE_LENGTH {
+                break;
+            }
+
+            // pointers only have 14 bits to address the target
+            if msg.len() <= 0x3FFF {
+                names.insert(suffix, msg.len());
+            }
+
+            msg.push(bytes.len() as u8);
+            msg.extend_from_slice(bytes);
+        }
+    }
+    msg.push(0);
+}
+
+/// Reads a domain name starting at `pos` and moves `pos` behind it.
+///
+/// Compression pointers are followed at any label position. Every pointer has to reference a location before the label sequence containing it, which rules out both forward references and loops.
+fn parse_domain_name(msg: &[u8], pos: &mut usize) -> Result<String, ParseError> {
     let mut domain = String::new();
-    let mut first = true;
-    let mut domain_pos = if is_backlink {
-        // compression is enabled
-        // mask the first two bits to get the position referenced
-        (u16::from_be_bytes([msg[*pos], msg[*pos + 1]]) & 16383u16) as usize
-    } else {
-        *pos
-    };
-
-    while msg[domain_pos] != 0 {
+    let mut domain_pos = *pos;
+    // start of the label sequence currently read, pointers must lie before it
+    let mut sequence_start = *pos;
+    // position behind the name in the original location, known once the first pointer is followed
+    let mut end = None;
+    // the length of the name in its wire format, including the terminating zero octet
+    let mut wire_length = 1;
+
+    loop {
+        let len = *msg
+            .get(domain_pos)
+            .ok_or_else(|| ParseError::new(ParseErrorKind::Truncated, domain_pos))?
+            as usize;
+
+        // check for compression, which is indicated by leading `11` in the length octet
+        if len & 192 == 192 {
+            // mask the first two bits to get the position referenced
+            let target = (read_u16(msg, domain_pos)? & 16383u16) as usize;
+            if target >= sequence_start {
+                return Err(ParseError::new(ParseErrorKind::BadPointer, domain_pos));
+            }
+
+            end.get_or_insert(domain_pos + 2);
+            sequence_start = target;
+            domain_pos = target;
+            continue;
+        }
+
+        if len == 0 {
+            break;
+        }
+
+        // labels are limited to 63 octets, names to 255 octets (RFC 1035, 2.3.4)
+        wire_length += len + 1;
+        if len > MAX_LABEL_LENGTH || wire_length > MAX_WIRE_LENGTH {
+            return Err(ParseError::new(ParseErrorKind::LabelOverflow, domain_pos));
+        }
+
         // append a dot in the domain name after the first sublabel
-        if !first {
+        if !domain.is_empty() {
             domain.push('.');
-        } else {
-            first = false;
         }
 
-        let len = msg[domain_pos] as usize;
-
-        domain.push_str(&String::from_utf8_lossy(
-            &msg[(domain_pos + 1)..(domain_pos + len + 1)],
-        ));
+        let label = msg
+            .get((domain_pos + 1)..(domain_pos + len + 1))
+            .ok_or_else(|| ParseError::new(ParseErrorKind::LabelOverflow, domain_pos))?;
+        domain.push_str(&String::from_utf8_lossy(label));
 
         domain_pos += len + 1;
     }
 
-    if is_backlink {
-        // if it is a link, just increment by 2
-        *pos += 2;
-    } else {
-        // if this was no link, set the pointer to the next element after the domain name
-        *pos = domain_pos + 1;
-    }
+    // continue after the first pointer, or after the terminating zero octet if there was none
+    *pos = end.unwrap_or(domain_pos + 1);
 
-    domain
+    Ok(domain)
+}
+
+/// Reads a big endian `u16` starting at `pos`.
+fn read_u16(msg: &[u8], pos: usize) -> Result<u16, ParseError> {
+    msg.get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| ParseError::new(ParseErrorKind::Truncated, pos))
+}
+
+/// Reads a big endian `u32` starting at `pos`.
+fn read_u32(msg: &[u8], pos: usize) -> Result<u32, ParseError> {
+    msg.get(pos..pos + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| ParseError::new(ParseErrorKind::Truncated, pos))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn replies_match_requests() {
+        let request = DNSMessage::new_request(4711, "chat.example".into());
+        let mut reply = request.clone();
+        reply.set_response();
+        assert!(reply.is_reply_to(&request));
+
+        reply.questions[0].name = "CHAT.example".into();
+        assert!(reply.is_reply_to(&request));
+
+        let mut other_id = reply.clone();
+        other_id.header.id = 4712;
+        assert!(!other_id.is_reply_to(&request));
+
+        let mut other_name = reply.clone();
+        other_name.questions[0].name = "other.example".into();
+        assert!(!other_name.is_reply_to(&request));
+
+        assert!(!request.is_reply_to(&request));
+    }
+
     #[test]
     fn conversion_to_u8_vec() {
         let expected = [
@@ -396,7 +669,7 @@ mod tests {
 
         let expected = DNSMessage::new_request(23481, "example.com".into());
 
-        let parsed = DNSMessage::from(input.as_slice());
+        let parsed = DNSMessage::try_from(input.as_slice()).unwrap();
         assert_eq!(parsed, expected);
     }
 
@@ -404,7 +677,7 @@ mod tests {
     fn two_way_conversion() {
         let message = DNSMessage::new_request(23481, "example.com".into());
         let msg: Vec<u8> = message.clone().into();
-        let parsed = DNSMessage::from(msg.as_slice());
+        let parsed = DNSMessage::try_from(msg.as_slice()).unwrap();
 
         assert_eq!(message, parsed);
     }
@@ -436,17 +709,20 @@ mod tests {
                 rtype: RecordType::TXT,
                 rclass: RecordClass::IN,
                 ttl: 0,
-                data_length: 36,
+                data_length: 37,
                 record: RecordData::Txt(vec!["2021-05-24T19:48:38.379390+02:00test".into()]),
             }]),
+            authorities: None,
+            additionals: None,
         };
         let msg: Vec<u8> = input.into();
 
+        // the answer name is compressed to a pointer to the question name
         let message: Vec<u8> = vec![
             91, 185, 129, 128, 0, 1, 0, 1, 0, 0, 0, 0, 4, 105, 102, 115, 114, 2, 100, 101, 0, 0,
-            16, 0, 1, 4, 105, 102, 115, 114, 2, 100, 101, 0, 0, 16, 0, 1, 0, 0, 0, 0, 0, 36, 36,
-            50, 48, 50, 49, 45, 48, 53, 45, 50, 52, 84, 49, 57, 58, 52, 56, 58, 51, 56, 46, 51, 55,
-            57, 51, 57, 48, 43, 48, 50, 58, 48, 48, 116, 101, 115, 116,
+            16, 0, 1, 192, 12, 0, 16, 0, 1, 0, 0, 0, 0, 0, 37, 36, 50, 48, 50, 49, 45, 48, 53, 45,
+            50, 52, 84, 49, 57, 58, 52, 56, 58, 51, 56, 46, 51, 55, 57, 51, 57, 48, 43, 48, 50, 58,
+            48, 48, 116, 101, 115, 116,
         ];
 
         assert_eq!(msg, message);
@@ -479,20 +755,282 @@ mod tests {
                 rtype: RecordType::TXT,
                 rclass: RecordClass::IN,
                 ttl: 0,
-                data_length: 36,
+                data_length: 37,
                 record: RecordData::Txt(vec!["2021-05-24T19:48:38.379390+02:00test".into()]),
             }]),
+            authorities: None,
+            additionals: None,
         };
 
         let input: Vec<u8> = vec![
             91, 185, 129, 128, 0, 1, 0, 1, 0, 0, 0, 0, 4, 105, 102, 115, 114, 2, 100, 101, 0, 0,
-            16, 0, 1, 4, 105, 102, 115, 114, 2, 100, 101, 0, 0, 16, 0, 1, 0, 0, 0, 0, 0, 36, 36,
+            16, 0, 1, 4, 105, 102, 115, 114, 2, 100, 101, 0, 0, 16, 0, 1, 0, 0, 0, 0, 0, 37, 36,
             50, 48, 50, 49, 45, 48, 53, 45, 50, 52, 84, 49, 57, 58, 52, 56, 58, 51, 56, 46, 51, 55,
             57, 51, 57, 48, 43, 48, 50, 58, 48, 48, 116, 101, 115, 116,
         ];
 
-        let answer = DNSMessage::from(input.as_slice());
+        let answer = DNSMessage::try_from(input.as_slice()).unwrap();
         // let msg = DNSMessage::from(message);
         assert_eq!(expected, answer);
     }
+
+    #[test]
+    fn truncated_header_is_rejected() {
+        let input: Vec<u8> = vec![91, 185, 1, 0, 0, 1];
+
+        let err = DNSMessage::try_from(input.as_slice()).unwrap_err();
+        assert_eq!(err, ParseError::new(ParseErrorKind::TruncatedHeader, 6));
+    }
+
+    #[test]
+    fn truncated_question_is_rejected() {
+        let input: Vec<u8> = vec![
+            91, 185, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111,
+            109, 0, 0, 16,
+        ];
+
+        let err = DNSMessage::try_from(input.as_slice()).unwrap_err();
+        assert_eq!(err, ParseError::new(ParseErrorKind::Truncated, 27));
+    }
+
+    #[test]
+    fn label_overflow_is_rejected() {
+        // label announces 100 bytes but the message ends long before that
+        let input: Vec<u8> = vec![
+            91, 185, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 100, 101, 120, 97, 109, 112, 108, 101, 0, 0, 16,
+            0, 1,
+        ];
+
+        let err = DNSMessage::try_from(input.as_slice()).unwrap_err();
+        assert_eq!(err, ParseError::new(ParseErrorKind::LabelOverflow, 12));
+    }
+
+    #[test]
+    fn forward_pointer_is_rejected() {
+        let input: Vec<u8> = vec![91, 185, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 192, 12, 0, 16, 0, 1];
+
+        let err = DNSMessage::try_from(input.as_slice()).unwrap_err();
+        assert_eq!(err, ParseError::new(ParseErrorKind::BadPointer, 12));
+    }
+
+    #[test]
+    fn unknown_type_is_rejected() {
+        let input: Vec<u8> = vec![
+            91, 185, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111,
+            109, 0, 0, 99, 0, 1,
+        ];
+
+        let err = DNSMessage::try_from(input.as_slice()).unwrap_err();
+        assert_eq!(err, ParseError::new(ParseErrorKind::UnknownType(99), 25));
+    }
+
+    #[test]
+    fn rdata_length_mismatch_is_rejected() {
+        // RDLENGTH of 4 while the contained string claims 36 bytes
+        let input: Vec<u8> = vec![
+            91, 185, 129, 128, 0, 1, 0, 1, 0, 0, 0, 0, 4, 105, 102, 115, 114, 2, 100, 101, 0, 0,
+            16, 0, 1, 4, 105, 102, 115, 114, 2, 100, 101, 0, 0, 16, 0, 1, 0, 0, 0, 0, 0, 4, 36, 50,
+            48, 50,
+        ];
+
+        let err = DNSMessage::try_from(input.as_slice()).unwrap_err();
+        assert_eq!(
+            err,
+            ParseError::new(
+                ParseErrorKind::RdataLengthMismatch {
+                    expected: 4,
+                    actual: 37
+                },
+                44
+            )
+        );
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let input: Vec<u8> = vec![
+            91, 185, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111,
+            109, 0, 0, 16, 0, 1, 42, 42,
+        ];
+
+        let err = DNSMessage::try_from(input.as_slice()).unwrap_err();
+        assert_eq!(err, ParseError::new(ParseErrorKind::TrailingBytes(2), 29));
+    }
+
+    #[test]
+    fn authority_and_additional_sections() {
+        let input: Vec<u8> = vec![
+            18, 52, 129, 128, 0, 1, 0, 1, 0, 1, 0, 1, 4, 105, 102, 115, 114, 2, 100, 101, 0, 0, 16,
+            0, 1, 4, 105, 102, 115, 114, 2, 100, 101, 0, 0, 16, 0, 1, 0, 0, 0, 0, 0, 3, 2, 104,
+            105, 2, 100, 101, 0, 0, 2, 0, 1, 0, 0, 14, 16, 0, 10, 1, 97, 3, 110, 105, 99, 2, 100,
+            101, 0, 1, 97, 3, 110, 105, 99, 2, 100, 101, 0, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 194, 0,
+            0, 53,
+        ];
+
+        let parsed = DNSMessage::try_from(input.as_slice()).unwrap();
+
+        assert_eq!(
+            parsed.answers,
+            Some(vec![DNSAnswer {
+                name: "ifsr.de".into(),
+                rtype: RecordType::TXT,
+                rclass: RecordClass::IN,
+                ttl: 0,
+                data_length: 3,
+                record: RecordData::Txt(vec!["hi".into()]),
+            }])
+        );
+        assert_eq!(
+            parsed.authorities,
+            Some(vec![DNSAnswer {
+                name: "de".into(),
+                rtype: RecordType::NS,
+                rclass: RecordClass::IN,
+                ttl: 3600,
+                data_length: 10,
+                record: RecordData::Unsupported(vec![1, 97, 3, 110, 105, 99, 2, 100, 101, 0]),
+            }])
+        );
+        assert_eq!(
+            parsed.additionals,
+            Some(vec![DNSAnswer {
+                name: "a.nic.de".into(),
+                rtype: RecordType::A,
+                rclass: RecordClass::IN,
+                ttl: 60,
+                data_length: 4,
+                record: RecordData::Unsupported(vec![194, 0, 0, 53]),
+            }])
+        );
+
+        // owner names are compressed on encoding, but the content stays the same
+        let encoded: Vec<u8> = parsed.clone().into();
+        assert!(encoded.len() < input.len());
+        assert_eq!(DNSMessage::try_from(encoded.as_slice()).unwrap(), parsed);
+    }
+
+    #[test]
+    fn unknown_record_types_are_kept() {
+        // the reply above, with an RRSIG record in the authority section and a record of unknown class
+        let mut input: Vec<u8> = vec![
+            18, 52, 129, 128, 0, 1, 0, 1, 0, 1, 0, 1, 4, 105, 102, 115, 114, 2, 100, 101, 0, 0, 16,
+            0, 1, 4, 105, 102, 115, 114, 2, 100, 101, 0, 0, 16, 0, 1, 0, 0, 0, 0, 0, 3, 2, 104,
+            105, 2, 100, 101, 0, 0, 2, 0, 1, 0, 0, 14, 16, 0, 10, 1, 97, 3, 110, 105, 99, 2, 100,
+            101, 0, 1, 97, 3, 110, 105, 99, 2, 100, 101, 0, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 194, 0,
+            0, 53,
+        ];
+        input[52] = 46;
+        input[84] = 254;
+
+        let parsed = DNSMessage::try_from(input.as_slice()).unwrap();
+        assert_eq!(parsed.answers.as_ref().unwrap().len(), 1);
+        let authority = &parsed.authorities.as_ref().unwrap()[0];
+        assert_eq!(authority.rtype, RecordType::Unknown(46));
+        assert_eq!(authority.data_length, 10);
+        assert_eq!(
+            parsed.additionals.as_ref().unwrap()[0].rclass,
+            RecordClass::Unknown(254)
+        );
+
+        let encoded: Vec<u8> = parsed.clone().into();
+        assert_eq!(DNSMessage::try_from(encoded.as_slice()).unwrap(), parsed);
+
+        // but records running past the end of the message are still rejected
+        assert!(DNSMessage::try_from(&input[..input.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn request_with_edns() {
+        let expected = [
+            91, 185, 1, 0, 0, 1, 0, 0, 0, 0, 0, 1, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111,
+            109, 0, 0, 16, 0, 1, 0, 0, 41, 16, 0, 0, 0, 0, 0, 0, 0,
+        ];
+
+        let message =
+            DNSMessage::new_request(23481, "example.com".into()).with_edns(Edns::new(4096));
+        let msg: Vec<u8> = message.clone().into();
+        assert_eq!(msg, expected);
+
+        let parsed = DNSMessage::try_from(msg.as_slice()).unwrap();
+        assert_eq!(parsed.edns(), Some(Edns::new(4096)));
+        assert_eq!(parsed, message);
+    }
+
+    #[test]
+    fn name_compression_uses_suffixes() {
+        let mut message = DNSMessage::new_request(23481, "ifsr.de".into());
+        message.add_answer(RecordData::Txt(vec!["hi".into()]));
+        message.authorities = Some(vec![DNSAnswer {
+            name: "chat.ifsr.de".into(),
+            rtype: RecordType::TXT,
+            rclass: RecordClass::IN,
+            ttl: 0,
+            data_length: 3,
+            record: RecordData::Txt(vec!["ho".into()]),
+        }]);
+        message.header.ns_record_count = 1;
+
+        let msg: Vec<u8> = message.clone().into();
+
+        let expected: Vec<u8> = vec![
+            91, 185, 129, 128, 0, 1, 0, 1, 0, 1, 0, 0, 4, 105, 102, 115, 114, 2, 100, 101, 0, 0,
+            16, 0, 1, 192, 12, 0, 16, 0, 1, 0, 0, 0, 0, 0, 3, 2, 104, 105, 4, 99, 104, 97, 116,
+            192, 12, 0, 16, 0, 1, 0, 0, 0, 0, 0, 3, 2, 104, 111,
+        ];
+        assert_eq!(msg, expected);
+        assert_eq!(DNSMessage::try_from(msg.as_slice()).unwrap(), message);
+    }
+
+    #[test]
+    fn invalid_names_are_rejected() {
+        assert!(check_domain_name("chat.example.org").is_ok());
+        assert!(check_domain_name("").is_ok());
+        assert!(check_domain_name("example.org.").is_err());
+        assert!(check_domain_name("a..b").is_err());
+        assert!(check_domain_name(&"a".repeat(64)).is_err());
+        assert!(check_domain_name(&vec!["a".repeat(63); 4].join(".")).is_err());
+        assert!(check_domain_name(&vec!["a".repeat(62); 4].join(".")).is_ok());
+    }
+
+    #[test]
+    fn invalid_names_are_written_as_valid_ones() {
+        for name in [
+            "a".repeat(300),
+            "a".repeat(100),
+            "example..org.".to_string(),
+            vec!["a".repeat(63); 5].join("."),
+        ]
+        .iter()
+        {
+            let msg: Vec<u8> = DNSMessage::new_request(1, name.clone()).into();
+            let parsed = DNSMessage::try_from(msg.as_slice()).unwrap();
+            assert!(check_domain_name(&parsed.questions[0].name).is_ok());
+        }
+    }
+
+    #[test]
+    fn pointer_after_first_label() {
+        // question for `example.com`, followed by an answer for `www.example.com` whose
+        // second label is a pointer
+        let input: Vec<u8> = vec![
+            91, 185, 129, 128, 0, 1, 0, 1, 0, 0, 0, 0, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99,
+            111, 109, 0, 0, 16, 0, 1, 3, 119, 119, 119, 192, 12, 0, 16, 0, 1, 0, 0, 0, 0, 0, 3, 2,
+            104, 105,
+        ];
+
+        let parsed = DNSMessage::try_from(input.as_slice()).unwrap();
+        assert_eq!(parsed.answers.unwrap()[0].name, "www.example.com");
+    }
+
+    #[test]
+    fn pointer_loop_is_rejected() {
+        // the answer name `a` points back to its own first label
+        let input: Vec<u8> = vec![
+            91, 185, 129, 128, 0, 0, 0, 1, 0, 0, 0, 0, 1, 97, 192, 12, 0, 16, 0, 1, 0, 0, 0, 0, 0,
+            0,
+        ];
+
+        let err = DNSMessage::try_from(input.as_slice()).unwrap_err();
+        assert_eq!(err, ParseError::new(ParseErrorKind::BadPointer, 14));
+    }
 }
@@ -1,2 +1,4 @@
+pub mod edns;
+pub mod error;
 pub mod messages;
 pub mod types;
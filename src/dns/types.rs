@@ -2,6 +2,9 @@
 //!
 //! Since only a small fraction of the whole DNS specification is needed for this application, not everything has been implemented.
 
+use super::error::ParseErrorKind;
+use std::convert::TryFrom;
+
 /// Type Fields used in Reqource records and also in questions.
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -38,6 +41,12 @@ pub enum RecordType {
     MX,
     /// text string
     TXT,
+    /// an IPv6 host address (RFC 3596)
+    AAAA,
+    /// EDNS pseudo-record (RFC 6891)
+    OPT,
+    /// Any other type, e.g. `RRSIG` records added by resolvers, which are only passed on
+    Unknown(u16),
 }
 
 impl From<RecordType> for u16 {
@@ -59,35 +68,43 @@ impl From<RecordType> for u16 {
             RecordType::MINFO => 14,
             RecordType::MX => 15,
             RecordType::TXT => 16,
+            RecordType::AAAA => 28,
+            RecordType::OPT => 41,
+            RecordType::Unknown(rtype) => rtype,
         }
     }
 }
 
-impl From<u16> for RecordType {
-    fn from(data: u16) -> Self {
+impl TryFrom<u16> for RecordType {
+    type Error = ParseErrorKind;
+
+    fn try_from(data: u16) -> Result<Self, Self::Error> {
         match data {
-            1 => RecordType::A,
-            2 => RecordType::NS,
-            3 => RecordType::MD,
-            4 => RecordType::MF,
-            5 => RecordType::CNAME,
-            6 => RecordType::SOA,
-            7 => RecordType::MB,
-            8 => RecordType::MG,
-            9 => RecordType::MR,
-            10 => RecordType::NULL,
-            11 => RecordType::WKS,
-            12 => RecordType::PTR,
-            13 => RecordType::HINFO,
-            14 => RecordType::MINFO,
-            15 => RecordType::MX,
-            16 => RecordType::TXT,
-            _ => panic!("Unsupported Record Type {}", data),
+            1 => Ok(RecordType::A),
+            2 => Ok(RecordType::NS),
+            3 => Ok(RecordType::MD),
+            4 => Ok(RecordType::MF),
+            5 => Ok(RecordType::CNAME),
+            6 => Ok(RecordType::SOA),
+            7 => Ok(RecordType::MB),
+            8 => Ok(RecordType::MG),
+            9 => Ok(RecordType::MR),
+            10 => Ok(RecordType::NULL),
+            11 => Ok(RecordType::WKS),
+            12 => Ok(RecordType::PTR),
+            13 => Ok(RecordType::HINFO),
+            14 => Ok(RecordType::MINFO),
+            15 => Ok(RecordType::MX),
+            16 => Ok(RecordType::TXT),
+            28 => Ok(RecordType::AAAA),
+            41 => Ok(RecordType::OPT),
+            _ => Err(ParseErrorKind::UnknownType(data)),
         }
     }
 }
 
 /// The class of a resource record
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum RecordClass {
     /// The Internet
@@ -98,6 +115,10 @@ pub enum RecordClass {
     CH,
     /// Hesoid
     HS,
+    /// Not a class at all: `OPT` records carry the sender's UDP payload size in the class field
+    OPT(u16),
+    /// Any other class of a record, which is only passed on
+    Unknown(u16),
 }
 
 impl From<RecordClass> for u16 {
@@ -107,18 +128,22 @@ impl From<RecordClass> for u16 {
             RecordClass::CS => 2,
             RecordClass::CH => 3,
             RecordClass::HS => 4,
+            RecordClass::OPT(payload_size) => payload_size,
+            RecordClass::Unknown(class) => class,
         }
     }
 }
 
-impl From<u16> for RecordClass {
-    fn from(data: u16) -> Self {
+impl TryFrom<u16> for RecordClass {
+    type Error = ParseErrorKind;
+
+    fn try_from(data: u16) -> Result<Self, Self::Error> {
         match data {
-            1 => RecordClass::IN,
-            2 => RecordClass::CS,
-            3 => RecordClass::CH,
-            4 => RecordClass::HS,
-            _ => panic!("Unsupported Record Class {:?}", data.to_be_bytes()),
+            1 => Ok(RecordClass::IN),
+            2 => Ok(RecordClass::CS),
+            3 => Ok(RecordClass::CH),
+            4 => Ok(RecordClass::HS),
+            _ => Err(ParseErrorKind::UnknownClass(data)),
         }
     }
 }
@@ -126,8 +151,19 @@ impl From<u16> for RecordClass {
 /// The RDATA field of a resource record. May not exceed 65,535 Bytes.
 #[derive(Clone, Debug, PartialEq)]
 pub enum RecordData {
-    /// A TXT record. One String may not be longer than 255 bytes.
-    Txt(Vec<String>),
-    /// Not supported record type
-    Unsupported,
+    /// A TXT record, made up of character-strings of up to 255 arbitrary bytes each.
+    Txt(Vec<Vec<u8>>),
+    /// The options of an EDNS `OPT` pseudo-record
+    Opt(Vec<EdnsOption>),
+    /// Not supported record type, kept as raw bytes so it can be passed on unaltered
+    Unsupported(Vec<u8>),
+}
+
+/// A single option of an EDNS `OPT` record, encoded as `{code, length, data}` on the wire.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EdnsOption {
+    /// The option code assigned by IANA
+    pub code: u16,
+    /// Option payload
+    pub data: Vec<u8>,
 }
@@ -0,0 +1,146 @@
+//! Reading files to send and saving the ones received.
+
+use crate::transport::{Attachment, ChatMessage};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use tokio::task;
+
+/// The largest file that may be sent, as every part of it takes a round trip to transfer.
+const MAX_FILE_SIZE: usize = 10 * 1024 * 1024;
+
+/// Reads the file at `path` into a message carrying it as attachment.
+pub fn attach(path: &Path) -> io::Result<ChatMessage> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+    let too_large = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("file exceeds {} MiB", MAX_FILE_SIZE / 1024 / 1024),
+        )
+    };
+    if fs::metadata(path)?.len() > MAX_FILE_SIZE as u64 {
+        return Err(too_large());
+    }
+    // the file may have grown since
+    let mut data = Vec::new();
+    File::open(path)?
+        .take(MAX_FILE_SIZE as u64 + 1)
+        .read_to_end(&mut data)?;
+    if data.len() > MAX_FILE_SIZE {
+        return Err(too_large());
+    }
+
+    let mut msg = ChatMessage::new(String::new());
+    msg.attachment = Some(Attachment { name, data });
+    Ok(msg)
+}
+
+/// Saves a received attachment into `dir`, returning the path it was written to.
+///
+/// Only the last component of the name chosen by the peer is used, and existing files are never overwritten.
+pub fn save(dir: &Path, attachment: &Attachment) -> io::Result<PathBuf> {
+    let name = match Path::new(&attachment.name).file_name() {
+        Some(name) => PathBuf::from(name),
+        None => PathBuf::from("download"),
+    };
+    let stem = name
+        .file_stem()
+        .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+    let extension = name
+        .extension()
+        .map_or_else(String::new, |ext| format!(".{}", ext.to_string_lossy()));
+    fs::create_dir_all(dir)?;
+
+    for n in 0.. {
+        let path = match n {
+            0 => dir.join(&name),
+            n => dir.join(format!("{} ({}){}", stem, n, extension)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                file.write_all(&attachment.data)?;
+                return Ok(path);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+/// Saves the attachment of a received message into `dir` off the runtime, replacing the text of the message with where it went.
+pub async fn save_received(dir: PathBuf, msg: ChatMessage) -> ChatMessage {
+    if msg.attachment.is_none() {
+        return msg;
+    }
+    // kept to still show who sent what if saving is cut short
+    let (id, nick, sent) = (msg.id, msg.nick.clone(), msg.sent);
+    task::spawn_blocking(move || {
+        let mut msg = msg;
+        if let Some(attachment) = &msg.attachment {
+            msg.text = match save(&dir, attachment) {
+                Ok(path) => format!("saved to {}", path.display()),
+                Err(e) => format!("could not be saved: {}", e),
+            };
+        }
+        msg
+    })
+    .await
+    .unwrap_or_else(|e| {
+        let mut msg = ChatMessage::new(format!("could not be saved: {}", e));
+        msg.id = id;
+        msg.nick = nick;
+        msg.sent = sent;
+        msg
+    })
+}
+
+/// Reads the file at `path` like `attach`, but off the runtime.
+pub async fn attach_async(path: PathBuf) -> io::Result<ChatMessage> {
+    task::spawn_blocking(move || attach(&path))
+        .await
+        .map_err(io::Error::other)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn attachments_do_not_overwrite_or_escape() {
+        let dir = env::temp_dir().join(format!("kakure-{}", rand::random::<u32>()));
+        let attachment = Attachment {
+            name: "../../etc/notes.txt".into(),
+            data: vec![0, 159, 146, 150],
+        };
+
+        let first = save(&dir, &attachment).unwrap();
+        let second = save(&dir, &attachment).unwrap();
+        assert_eq!(first, dir.join("notes.txt"));
+        assert_eq!(second, dir.join("notes (1).txt"));
+        assert_eq!(fs::read(&second).unwrap(), attachment.data);
+
+        let attached = attach(&first).unwrap().attachment.unwrap();
+        assert_eq!(attached.name, "notes.txt");
+        assert_eq!(attached.data, attachment.data);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn large_files_are_rejected_before_reading() {
+        let path = env::temp_dir().join(format!("kakure-{}.bin", rand::random::<u32>()));
+        // a sparse file, which would take far longer to read than to check
+        File::create(&path)
+            .unwrap()
+            .set_len(1024 * 1024 * 1024)
+            .unwrap();
+
+        let error = attach(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        fs::remove_file(path).unwrap();
+    }
+}
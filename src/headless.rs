@@ -0,0 +1,185 @@
+//! A frontend without a terminal, for scripts, bots and tests.
+//!
+//! Every line read from stdin is handled like a line typed into the Compose box, so commands work as well.
+//! Received messages are written to stdout one per line, either as plain text or as JSON objects (JSON Lines). Everything else, like the output of commands, goes to stderr.
+
+use crate::hub::{Event, Hub};
+use crate::state::{self, Action, MessageType, State};
+use crate::transport::{ChatMessage, Transport};
+use chrono::SecondsFormat;
+use serde_json::{json, Value};
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+/// How received messages are written to stdout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// A line of text like in saved conversations
+    Plain,
+    /// A JSON object holding timestamp, sender and text
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "plain" => Ok(Format::Plain),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown format {}, expected plain or json", format)),
+        }
+    }
+}
+
+/// Formats a received message as a line of output, without the line break.
+///
+/// In plain text, line breaks and other control characters chosen by the peer are escaped like `\n`, so every message takes a single line and cannot mess with the terminal. In JSON, the sender is the nickname of the author or `null` if they did not choose one.
+pub fn format_message(msg: &ChatMessage, format: Format) -> String {
+    match format {
+        Format::Plain => escape(&state::plain_text(msg, &MessageType::Received)),
+        Format::Json => message_json(msg).to_string(),
+    }
+}
+
+/// Escapes backslashes and control characters, e.g. a line break as `\n` or an escape as `\u{1b}`.
+fn escape(line: &str) -> String {
+    let mut escaped = String::with_capacity(line.len());
+    for c in line.chars() {
+        if c == '\\' || c.is_control() {
+            escaped.extend(c.escape_default());
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// A message as JSON object holding timestamp, sender and text, as well as whether it is an action and the name of its attachment.
+pub fn message_json(msg: &ChatMessage) -> Value {
+    json!({
+        "timestamp": msg.sent.to_rfc3339_opts(SecondsFormat::Millis, false),
+        "sender": msg.nick,
+        "text": msg.text,
+        "action": msg.action,
+        "file": msg.attachment.as_ref().map(|attachment| &attachment.name),
+    })
+}
+
+/// Runs the frontend on the conversation of `hub` until stdin ends and all messages sent were delivered, or until `/quit`.
+///
+/// Like in `tui::run`, `/connect` changes the target of `polling`.
+pub async fn run(
+    hub: Arc<Hub>,
+    polling: Option<Arc<dyn Transport>>,
+    format: Format,
+) -> io::Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut reading = true;
+    let mut events = hub.subscribe();
+
+    loop {
+        if !reading && !has_undelivered(&hub.state()) {
+            break;
+        }
+
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(Event::Received(msg)) => println!("{}", format_message(&msg, format)),
+                Some(Event::Changed) => (),
+                None => break,
+            },
+
+            line = lines.next_line(), if reading => {
+                let line = match line? {
+                    Some(line) => line,
+                    None => {
+                        reading = false;
+                        continue;
+                    }
+                };
+                let (shown, action) = {
+                    let mut state = hub.state();
+                    let shown = state.messages.len();
+                    line.trim_end_matches('\r')
+                        .chars()
+                        .for_each(|c| state.add_input_char(c));
+                    (shown, state.submit())
+                };
+                match action {
+                    Action::None => (),
+                    Action::Send(msg) => {
+                        if let Err(e) = hub.send(msg) {
+                            hub.state().add_system(format!("could not send: {}", e));
+                        }
+                    }
+                    Action::SendFile(path) => {
+                        if let Err(e) = hub.send_file(path.clone()).await {
+                            hub.state().add_system(format!("could not send {}: {}", path.display(), e));
+                        }
+                    }
+                    Action::Connect { host, port } => {
+                        hub.connect(polling.as_deref(), host, port).await
+                    }
+                    Action::Quit => break,
+                }
+                let state = hub.state();
+                // the input was taken as a message or a command, so report what happened
+                for (msg, ty) in state.messages.get(shown..).unwrap_or_default() {
+                    if *ty == MessageType::System {
+                        eprintln!("-!- {}", msg.text);
+                    }
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether any message sent is still waiting to be delivered.
+fn has_undelivered(state: &State) -> bool {
+    state
+        .messages
+        .iter()
+        .any(|(_, ty)| matches!(ty, MessageType::Queued | MessageType::Failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::Attachment;
+    use chrono::DateTime;
+
+    #[test]
+    fn messages_are_written_as_lines() {
+        let mut msg = ChatMessage::new("hello\nworld\r\u{1b}[2J\\".into());
+        msg.sent =
+            DateTime::from(DateTime::parse_from_rfc3339("2020-12-24T18:34:16+01:00").unwrap());
+        msg.nick = Some("alice".into());
+
+        let plain = format_message(&msg, Format::Plain);
+        assert!(plain.ends_with(r"<alice> hello\nworld\r\u{1b}[2J\\"));
+        assert!(!plain.contains(|c: char| c.is_control()));
+
+        let json = format_message(&msg, Format::Json);
+        assert!(!json.contains('\n'));
+        let parsed: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["sender"], "alice");
+        assert_eq!(parsed["text"], msg.text);
+        assert_eq!(parsed["file"], Value::Null);
+        let timestamp = DateTime::parse_from_rfc3339(parsed["timestamp"].as_str().unwrap());
+        assert_eq!(timestamp.unwrap(), msg.sent);
+
+        msg.nick = None;
+        msg.attachment = Some(Attachment {
+            name: "a.txt".into(),
+            data: Vec::new(),
+        });
+        let parsed: Value = serde_json::from_str(&format_message(&msg, Format::Json)).unwrap();
+        assert_eq!(parsed["sender"], Value::Null);
+        assert_eq!(parsed["file"], "a.txt");
+    }
+}
@@ -0,0 +1,210 @@
+//! Chat history kept on disk between sessions.
+//!
+//! Every conversation is stored in its own file, named after the peer, with one line per message of the form `<direction>\t<timestamp>\t<file name>\t<text>\t<nickname>`.
+//! The direction is `S` for sent and `R` for received messages, followed by `*` for actions (see `/me`).
+//! Tabs, line breaks and backslashes within fields are escaped, the file name is empty for messages without an attachment and the nickname for authors without one.
+//! Lines written before nicknames were kept lack the last field.
+//! Messages are only ever appended, except for dropping the oldest ones beyond the retention limit when the history is opened.
+
+use crate::state::MessageType;
+use crate::transport::{Attachment, ChatMessage};
+use chrono::{DateTime, SecondsFormat};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The messages exchanged with a single peer, stored in a file.
+pub struct History {
+    file: File,
+}
+
+impl History {
+    /// Opens the history of the conversation with `peer` stored in `dir`, returning it along with its `limit` most recent messages.
+    ///
+    /// Older messages are removed from the file. Lines that cannot be parsed are skipped.
+    pub fn open(
+        dir: &Path,
+        peer: &str,
+        limit: usize,
+    ) -> io::Result<(Self, Vec<(ChatMessage, MessageType)>)> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(file_name(peer));
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let lines: Vec<&str> = contents.lines().collect();
+        let kept = &lines[lines.len().saturating_sub(limit)..];
+        if kept.len() < lines.len() {
+            compact(&path, kept)?;
+        }
+
+        let messages = kept.iter().filter_map(|line| parse_line(line)).collect();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok((Self { file }, messages))
+    }
+
+    /// Appends a message to the history.
+    pub fn append(&mut self, msg: &ChatMessage, ty: &MessageType) -> io::Result<()> {
+        writeln!(self.file, "{}", format_line(msg, ty))
+    }
+}
+
+/// The name of the file storing the conversation with `peer`, with all characters not safe in file names replaced.
+fn file_name(peer: &str) -> String {
+    let name: String = peer
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    format!("{}.log", name.trim_start_matches('.'))
+}
+
+/// Replaces the file at `path` with `lines`, writing them to a temporary file first so that a crash does not lose the history.
+fn compact(path: &Path, lines: &[&str]) -> io::Result<()> {
+    let mut temporary = PathBuf::from(path);
+    temporary.set_extension("tmp");
+    let mut file = File::create(&temporary)?;
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+    file.sync_all()?;
+    fs::rename(temporary, path)
+}
+
+fn format_line(msg: &ChatMessage, ty: &MessageType) -> String {
+    let direction = match (ty, msg.action) {
+        (MessageType::Received, false) => "R",
+        (MessageType::Received, true) => "R*",
+        // whether the message gets delivered is only known later
+        (_, false) => "S",
+        (_, true) => "S*",
+    };
+    let name = msg
+        .attachment
+        .as_ref()
+        .map_or("", |attachment| attachment.name.as_str());
+    format!(
+        "{}\t{}\t{}\t{}\t{}",
+        direction,
+        msg.sent.to_rfc3339_opts(SecondsFormat::Secs, false),
+        escape(name),
+        escape(&msg.text),
+        escape(msg.nick.as_deref().unwrap_or(""))
+    )
+}
+
+fn parse_line(line: &str) -> Option<(ChatMessage, MessageType)> {
+    let mut fields = line.splitn(5, '\t');
+    let (ty, action) = match fields.next()? {
+        "S" => (MessageType::Sent, false),
+        "S*" => (MessageType::Sent, true),
+        "R" => (MessageType::Received, false),
+        "R*" => (MessageType::Received, true),
+        _ => return None,
+    };
+    let sent = DateTime::from(DateTime::parse_from_rfc3339(fields.next()?).ok()?);
+    let name = unescape(fields.next()?)?;
+    let text = unescape(fields.next()?)?;
+    let nick = unescape(fields.next().unwrap_or(""))?;
+
+    let mut msg = ChatMessage::new(text);
+    msg.sent = sent;
+    msg.nick = Some(nick).filter(|nick| !nick.is_empty());
+    msg.action = action;
+    if !name.is_empty() {
+        // the data of attachments is not kept, it was saved elsewhere
+        msg.attachment = Some(Attachment {
+            name,
+            data: Vec::new(),
+        });
+    }
+    Some((msg, ty))
+}
+
+/// Escapes the characters separating fields and lines.
+fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverts `escape`, returning `None` for unknown escape sequences.
+fn unescape(field: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        unescaped.push(match chars.next()? {
+            '\\' => '\\',
+            't' => '\t',
+            'n' => '\n',
+            'r' => '\r',
+            _ => return None,
+        });
+    }
+    Some(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn messages_survive_restarts() {
+        let dir = env::temp_dir().join(format!("kakure-{}", rand::random::<u32>()));
+
+        let (mut history, messages) = History::open(&dir, "10.0.0.1", 2).unwrap();
+        assert!(messages.is_empty());
+        for text in ["first", "second\twith\ttabs", "third\nwith a \\ line break"].iter() {
+            history
+                .append(&ChatMessage::new(text.to_string()), &MessageType::Sent)
+                .unwrap();
+        }
+        let mut file = ChatMessage::new("saved to downloads/a.txt".into());
+        file.nick = Some("bob".into());
+        file.attachment = Some(Attachment {
+            name: "a.txt".into(),
+            data: Vec::new(),
+        });
+        history.append(&file, &MessageType::Received).unwrap();
+        drop(history);
+
+        // only the most recent messages are kept
+        let (_, messages) = History::open(&dir, "10.0.0.1", 2).unwrap();
+        let texts: Vec<&str> = messages.iter().map(|(msg, _)| msg.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec!["third\nwith a \\ line break", file.text.as_str()]
+        );
+        assert!(matches!(messages[0].1, MessageType::Sent));
+        assert_eq!(messages[1].0.attachment, file.attachment);
+        assert_eq!(messages[1].0.nick, file.nick);
+        assert_eq!(messages[0].0.nick, None);
+        assert_eq!(messages[1].0.sent.timestamp(), file.sent.timestamp());
+
+        // lines written before nicknames were kept are still read
+        assert!(parse_line("R\t2020-12-24T18:34:16+01:00\t\thello").is_some());
+
+        // other peers have a history of their own
+        let (_, messages) = History::open(&dir, "../10.0.0.2", 2).unwrap();
+        assert!(messages.is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+//! The conversation shared by all frontends, i.e. the user interface and clients of the control API.
+//!
+//! The hub applies what the transport reports to the state and tells every frontend about it, so a message sent by one frontend shows up in all others.
+
+use crate::files;
+use crate::state::{MessageType, State};
+use crate::transport::{ChatMessage, Transport, Update};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
+
+/// What frontends are told about.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// A message was received from the peer, with attachments already saved
+    Received(ChatMessage),
+    /// Anything else about the state changed
+    Changed,
+}
+
+/// The events of the hub for a single frontend.
+///
+/// Received messages are never dropped, even if the frontend falls behind, while changes of anything else are merged until the frontend gets to them, as it only needs to look at the latest state.
+pub struct Subscription {
+    received: UnboundedReceiver<ChatMessage>,
+    changes: watch::Receiver<()>,
+}
+
+impl Subscription {
+    /// Waits for the next event, returning `None` once the hub is gone.
+    pub async fn recv(&mut self) -> Option<Event> {
+        tokio::select! {
+            // messages first, as they change the state as well
+            biased;
+            msg = self.received.recv() => msg.map(Event::Received),
+            changed = self.changes.changed() => changed.ok().map(|()| Event::Changed),
+        }
+    }
+}
+
+/// The state of the conversation along with the way to the transport.
+pub struct Hub {
+    state: Mutex<State>,
+    /// Messages for the transport to send
+    outgoing: UnboundedSender<ChatMessage>,
+    /// Where received messages go, one channel per frontend
+    subscribers: Mutex<Vec<UnboundedSender<ChatMessage>>>,
+    changes: watch::Sender<()>,
+    /// Where received files are saved
+    downloads: PathBuf,
+}
+
+impl Hub {
+    pub fn new(
+        state: State,
+        outgoing: UnboundedSender<ChatMessage>,
+        downloads: PathBuf,
+    ) -> Arc<Self> {
+        let (changes, _) = watch::channel(());
+        Arc::new(Self {
+            state: Mutex::new(state),
+            outgoing,
+            subscribers: Mutex::new(Vec::new()),
+            changes,
+            downloads,
+        })
+    }
+
+    /// Locks the state, which must not be held across an `await`.
+    pub fn state(&self) -> MutexGuard<'_, State> {
+        // the state stays usable even if a frontend panicked while holding it
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Subscribes to all further events.
+    pub fn subscribe(&self) -> Subscription {
+        let (sender, received) = mpsc::unbounded_channel();
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(sender);
+        Subscription {
+            received,
+            changes: self.changes.subscribe(),
+        }
+    }
+
+    /// Tells all frontends that the state changed.
+    fn changed(&self) {
+        self.changes.send_replace(());
+    }
+
+    /// Passes a received message on to all frontends, forgetting the ones that went away.
+    fn received(&self, msg: &ChatMessage) {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|subscriber| subscriber.send(msg.clone()).is_ok());
+    }
+
+    /// Passes a message stored with `State::send` on to the transport.
+    ///
+    /// Fails if the transport stopped, in which case the message is marked as failed.
+    pub fn send(&self, msg: ChatMessage) -> io::Result<()> {
+        let id = msg.id;
+        let result = self.outgoing.send(msg).map_err(|_| {
+            self.state().update_delivery(id, MessageType::Failed);
+            io::Error::new(io::ErrorKind::BrokenPipe, "the transport stopped")
+        });
+        self.changed();
+        result
+    }
+
+    /// Reads the file at `path` without holding the state and sends it like `send`.
+    pub async fn send_file(&self, path: PathBuf) -> io::Result<()> {
+        let msg = files::attach_async(path).await?;
+        let msg = self.state().send(msg);
+        self.send(msg)
+    }
+
+    /// Sends all further queries of `transport` to `host` and `port`, telling the user how that went.
+    ///
+    /// Without `transport`, i.e. when only serving, there is nobody sending queries, so the target cannot be changed.
+    pub async fn connect(&self, transport: Option<&dyn Transport>, host: String, port: u16) {
+        let result = match transport {
+            Some(transport) => transport.set_target(host.clone(), port).await,
+            None => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only serving, so no queries are sent",
+            )),
+        };
+        let result = match result {
+            Ok(()) => "sending queries there from now on".to_string(),
+            Err(e) => format!("failed: {}", e),
+        };
+        self.state()
+            .add_system(format!("connect to {}:{}: {}", host, port, result));
+    }
+
+    /// Applies the updates of the transport to the state until the transport stops.
+    pub async fn dispatch(self: Arc<Self>, mut updates: UnboundedReceiver<Update>) {
+        while let Some(update) = updates.recv().await {
+            // attachments are written before locking, as that may take a while
+            let update = match update {
+                Update::Message(msg) => {
+                    Update::Message(files::save_received(self.downloads.clone(), msg).await)
+                }
+                update => update,
+            };
+            let mut state = self.state();
+            match update {
+                Update::Message(msg) => {
+                    state.add_received(msg.clone());
+                    drop(state);
+                    self.received(&msg);
+                    continue;
+                }
+                Update::Progress(progress) => state.update_progress(progress),
+                Update::Delivered(id) => state.update_delivery(id, MessageType::Delivered),
+                Update::Undelivered(id) => state.update_delivery(id, MessageType::Failed),
+                Update::Activity(activity) => state.record_activity(activity),
+            }
+            drop(state);
+            self.changed();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::Activity;
+
+    #[tokio::test]
+    async fn updates_reach_all_frontends() {
+        let (outgoing, mut sent) = mpsc::unbounded_channel();
+        let hub = Hub::new(State::new(), outgoing, PathBuf::from("downloads"));
+        let (updates, recv) = mpsc::unbounded_channel();
+        tokio::spawn(hub.clone().dispatch(recv));
+        let mut first = hub.subscribe();
+        let mut second = hub.subscribe();
+
+        let msg = hub.state().send(ChatMessage::new("hello".into()));
+        hub.send(msg.clone()).unwrap();
+        assert_eq!(sent.recv().await, Some(msg.clone()));
+        assert!(matches!(first.recv().await, Some(Event::Changed)));
+
+        updates.send(Update::Delivered(msg.id)).unwrap();
+        assert!(matches!(first.recv().await, Some(Event::Changed)));
+        updates
+            .send(Update::Message(ChatMessage::new("hi".into())))
+            .unwrap();
+        assert!(matches!(first.recv().await, Some(Event::Received(msg)) if msg.text == "hi"));
+        // changes not seen yet are merged
+        assert!(matches!(second.recv().await, Some(Event::Received(_))));
+        assert!(matches!(second.recv().await, Some(Event::Changed)));
+
+        let types: Vec<MessageType> = hub
+            .state()
+            .messages
+            .iter()
+            .map(|(_, ty)| ty.clone())
+            .collect();
+        assert_eq!(types, vec![MessageType::Delivered, MessageType::Received]);
+    }
+
+    #[test]
+    fn sending_fails_without_transport() {
+        let (outgoing, sent) = mpsc::unbounded_channel();
+        let hub = Hub::new(State::new(), outgoing, PathBuf::from("downloads"));
+        drop(sent);
+
+        let msg = hub.state().send(ChatMessage::new("hello".into()));
+        assert!(hub.send(msg).is_err());
+        assert_eq!(hub.state().messages[0].1, MessageType::Failed);
+    }
+
+    #[tokio::test]
+    async fn messages_are_not_dropped() {
+        let (outgoing, _sent) = mpsc::unbounded_channel();
+        let hub = Hub::new(State::new(), outgoing, PathBuf::from("downloads"));
+        let (updates, recv) = mpsc::unbounded_channel();
+        let mut subscription = hub.subscribe();
+
+        // e.g. the history of a room, received at once
+        for i in 0..1000 {
+            updates
+                .send(Update::Message(ChatMessage::new(i.to_string())))
+                .unwrap();
+            updates.send(Update::Activity(Activity::Queried)).unwrap();
+        }
+        drop(updates);
+        hub.clone().dispatch(recv).await;
+
+        for i in 0..1000 {
+            match subscription.recv().await {
+                Some(Event::Received(msg)) => assert_eq!(msg.text, i.to_string()),
+                event => panic!("expected message {}, got {:?}", i, event),
+            }
+        }
+        assert!(matches!(subscription.recv().await, Some(Event::Changed)));
+    }
+
+    #[tokio::test]
+    async fn files_are_sent_and_saved() {
+        let dir = std::env::temp_dir().join(format!("kakure-{}", rand::random::<u32>()));
+        let (outgoing, mut sent) = mpsc::unbounded_channel();
+        let hub = Hub::new(State::new(), outgoing, dir.clone());
+        let (updates, recv) = mpsc::unbounded_channel();
+        let mut subscription = hub.subscribe();
+
+        let path = dir.join("notes.txt");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, "hello").unwrap();
+        hub.send_file(path).await.unwrap();
+        let msg = sent.recv().await.unwrap();
+        assert_eq!(msg.attachment.as_ref().unwrap().data, b"hello");
+        assert!(hub.send_file(dir.join("missing")).await.is_err());
+
+        updates.send(Update::Message(msg)).unwrap();
+        drop(updates);
+        hub.clone().dispatch(recv).await;
+        let received = loop {
+            if let Some(Event::Received(msg)) = subscription.recv().await {
+                break msg;
+            }
+        };
+        let saved = dir.join("notes (1).txt");
+        assert_eq!(received.text, format!("saved to {}", saved.display()));
+        assert_eq!(std::fs::read(&saved).unwrap(), b"hello");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn connecting_fails_when_only_serving() {
+        let (outgoing, _sent) = mpsc::unbounded_channel();
+        let hub = Hub::new(State::new(), outgoing, PathBuf::from("downloads"));
+
+        hub.connect(None, "localhost".into(), 53).await;
+        let (msg, ty) = &hub.state().messages[0];
+        assert_eq!(*ty, MessageType::System);
+        assert!(msg.text.contains("failed"), "{}", msg.text);
+    }
+}
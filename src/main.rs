@@ -1,36 +1,212 @@
 use clap::Clap;
-use opts::Opts;
-use std::sync::mpsc;
-use std::thread;
+use history::History;
+use hub::Hub;
+use opts::{Command, Opts};
+use state::State;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use transport::crypto::{self, Cipher, PublicKey};
+use transport::network::{Tcp, Udp};
+use transport::{Codec, Transport};
 
+mod commands;
+#[cfg(unix)]
+mod control;
 mod dns;
+mod files;
+mod headless;
+mod history;
+mod hub;
 mod opts;
+mod relay;
 mod state;
 mod transport;
 mod tui;
 
-fn main() -> std::io::Result<()> {
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> io::Result<()> {
     let Opts {
         target,
         target_port,
         listening_port,
+        udp,
+        zone,
+        upstream,
+        serve,
+        relay,
+        room,
+        key,
+        peer_key,
+        downloads,
+        history_dir,
+        history_limit,
+        no_history,
+        nick,
+        headless,
+        output,
+        control,
+        command,
     } = Opts::parse();
 
-    let (msg_sender, rx) = mpsc::channel();
-    let sender = thread::Builder::new().name("Sender".to_string());
-    sender
-        .spawn(move || transport::sender::run_sender(rx, listening_port))
-        .expect("Could not spawn sender thread");
+    if let Some(Command::Keygen { file }) = command {
+        let secret = crypto::generate_key();
+        crypto::save_secret_key(&file, &secret)?;
+        println!(
+            "{}",
+            crypto::format_key(PublicKey::from(&secret).as_bytes())
+        );
+        return Ok(());
+    }
+
+    if let Some(room) = &room {
+        relay::check_room(room, &zone).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("invalid room: {}", e))
+        })?;
+    }
+
+    // conversations are told apart by whom we talk to, or where others reach us
+    let peer = match (&target, &room) {
+        (Some(target), Some(room)) => format!("{}-{}", target, room),
+        (Some(target), None) => target.clone(),
+        (None, _) => zone.clone(),
+    };
+    let transport = network_transport(udp, listening_port, target, target_port);
+
+    if relay {
+        return relay::run_relay(transport, zone).await;
+    }
 
-    let (sx, msg_recv) = mpsc::channel();
-    let receiver = thread::Builder::new().name("Receiver".to_string());
-    receiver
-        .spawn(move || transport::receiver::poll_messages(sx, (target, target_port)))
-        .expect("Could not spawn receiver thread");
+    let codec = match (key, peer_key) {
+        (Some(key), Some(peer_key)) => Codec::new(Some(load_cipher(&key, &peer_key)?)),
+        _ => Codec::default(),
+    };
 
-    if let Err(e) = tui::run(msg_sender, msg_recv) {
-        eprintln!("{}", e);
+    let mut state = if no_history {
+        State::new()
+    } else {
+        let (history, messages) = History::open(&history_dir, &peer, history_limit)?;
+        State::with_history(history, messages)
+    };
+    state.nick = nick;
+
+    let (msg_sender, rx) = mpsc::unbounded_channel();
+    let (sx, msg_recv) = mpsc::unbounded_channel();
+
+    // when carrying messages upstream, the receiver takes care of sending as well
+    let (outgoing, sending) = if upstream || room.is_some() {
+        (Some(rx), None)
+    } else {
+        let sending = tokio::spawn(transport::sender::run_sender(
+            rx,
+            sx.clone(),
+            transport.clone(),
+            zone.clone(),
+            codec.clone(),
+        ));
+        (None, Some(sending))
+    };
+
+    // only the transport of the poller sends queries, which `/connect` redirects
+    let polling = if serve {
+        None
+    } else {
+        tokio::spawn(transport::receiver::poll_messages(
+            sx,
+            transport.clone(),
+            zone,
+            room,
+            outgoing,
+            codec,
+        ));
+        Some(transport)
+    };
+
+    let hub = Hub::new(state, msg_sender, downloads);
+    tokio::spawn(hub.clone().dispatch(msg_recv));
+    if let Some(path) = control {
+        serve_control(path, hub.clone())?;
     }
 
+    let frontend = async {
+        if headless {
+            headless::run(hub, polling, output).await
+        } else {
+            if let Err(e) = tui::run(hub, polling).await {
+                eprintln!("{}", e);
+            }
+            Ok(())
+        }
+    };
+    // there is no point in chatting on if nobody can poll our messages
+    tokio::select! {
+        result = frontend => result,
+        result = sender_failure(sending) => result,
+    }
+}
+
+/// Waits for the task answering queries to fail, and forever if there is none.
+async fn sender_failure(sending: Option<JoinHandle<io::Result<()>>>) -> io::Result<()> {
+    let served = match sending {
+        Some(sending) => sending.await,
+        None => std::future::pending().await,
+    };
+    match served {
+        Ok(Ok(())) => std::future::pending().await,
+        Ok(Err(e)) => Err(io::Error::new(
+            e.kind(),
+            format!(
+                "could not serve requests: {}. Is something else running?",
+                e
+            ),
+        )),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+/// Serves the control API at `path` in the background.
+#[cfg(unix)]
+fn serve_control(path: PathBuf, hub: Arc<Hub>) -> io::Result<()> {
+    tokio::spawn(async move {
+        if let Err(e) = control::serve(&path, hub).await {
+            eprintln!("[control] Stopped serving {}: {}", path.display(), e);
+        }
+    });
     Ok(())
 }
+
+#[cfg(not(unix))]
+fn serve_control(_path: PathBuf, _hub: Arc<Hub>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the control API needs Unix sockets",
+    ))
+}
+
+/// Sets up the transport serving on `listening_port` and querying `target`, if given.
+fn network_transport(
+    udp: bool,
+    listening_port: u16,
+    target: Option<String>,
+    target_port: u16,
+) -> Arc<dyn Transport> {
+    match (udp, target) {
+        (true, Some(target)) => Arc::new(Udp::new(listening_port).with_target(target, target_port)),
+        (true, None) => Arc::new(Udp::new(listening_port)),
+        (false, Some(target)) => {
+            Arc::new(Tcp::new(listening_port).with_target(target, target_port))
+        }
+        (false, None) => Arc::new(Tcp::new(listening_port)),
+    }
+}
+
+/// Derives the cipher for chatting with the owner of `peer_key` from our secret key stored at `key_file`.
+fn load_cipher(key_file: &Path, peer_key: &str) -> io::Result<Cipher> {
+    let secret = crypto::load_secret_key(key_file)?;
+    crypto::parse_key(peer_key)
+        .map(PublicKey::from)
+        .and_then(|peer| Cipher::new(&secret, &peer))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid peer key"))
+}
@@ -1,19 +1,113 @@
-use clap::Clap;
+use crate::dns::messages;
+use crate::headless::Format;
+use clap::{AppSettings, Clap};
+use std::path::PathBuf;
 
 #[derive(Clap)]
 #[clap(
     name = "kakure",
     version = "1.0",
     author = "Felix Wittwer <hallo@felixwittwer.de>",
-    about = "A stupid simple proof-of-concept chat application that uses the DNS protocol to exchange messages."
+    about = "A stupid simple proof-of-concept chat application that uses the DNS protocol to exchange messages.",
+    setting = AppSettings::SubcommandsNegateReqs
 )]
 pub struct Opts {
     /// Target IP address
-    pub target: String,
+    #[clap(required_unless_present_any = &["serve", "relay"])]
+    pub target: Option<String>,
     /// Target port on the other side.
     #[clap(short, long, default_value = "53")]
     pub target_port: u16,
     /// Port the client listens on.
     #[clap(short, long, default_value = "53")]
     pub listening_port: u16,
+    /// Exchange messages via UDP, falling back to TCP for truncated replies.
+    #[clap(short, long)]
+    pub udp: bool,
+    /// Domain under which messages are exchanged.
+    #[clap(short, long, default_value = "ifsr.de", parse(try_from_str = parse_zone))]
+    pub zone: String,
+    /// Carry outgoing messages in query names instead of running a listener, for peers behind NAT.
+    #[clap(long, conflicts_with = "serve")]
+    pub upstream: bool,
+    /// Only answer queries without polling a target, e.g. for peers using `--upstream`.
+    #[clap(long)]
+    pub serve: bool,
+    /// Run a relay for chat rooms below the zone instead of chatting.
+    #[clap(long, conflicts_with_all = &["serve", "upstream", "room"])]
+    pub relay: bool,
+    /// Chat in a room of the relay at the target, implies `--upstream`.
+    #[clap(long, conflicts_with = "serve")]
+    pub room: Option<String>,
+    /// File holding our secret key, enables end-to-end encryption.
+    #[clap(long, requires = "peer-key")]
+    pub key: Option<PathBuf>,
+    /// Public key of the peer, as printed by `keygen` and verified out of band.
+    #[clap(long, requires = "key")]
+    pub peer_key: Option<String>,
+    /// Name to send our messages with, which can be changed with `/nick`.
+    #[clap(long)]
+    pub nick: Option<String>,
+    /// Directory to save received files in.
+    #[clap(long, default_value = "downloads")]
+    pub downloads: PathBuf,
+    /// Directory to keep the chat history in, one file per peer.
+    #[clap(long, default_value = "history")]
+    pub history_dir: PathBuf,
+    /// Number of messages kept in the history of each peer.
+    #[clap(long, default_value = "1000")]
+    pub history_limit: usize,
+    /// Neither load nor save the chat history.
+    #[clap(long)]
+    pub no_history: bool,
+    /// Read messages from stdin and write received ones to stdout instead of running the user interface.
+    #[clap(long)]
+    pub headless: bool,
+    /// How `--headless` writes received messages, `plain` or `json` for JSON Lines.
+    #[clap(long, default_value = "plain")]
+    pub output: Format,
+    /// Serve the control API on a Unix socket at this path, for other programs to chat along.
+    #[clap(long)]
+    pub control: Option<PathBuf>,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Clap)]
+pub enum Command {
+    /// Generate a key pair for end-to-end encryption, printing the public key.
+    Keygen {
+        /// File to write the secret key to
+        file: PathBuf,
+    },
+}
+
+/// Parses the zone, which may be given as absolute name with a trailing dot.
+///
+/// The root zone is rejected, as the names of queries below it would end in an empty label.
+fn parse_zone(zone: &str) -> Result<String, String> {
+    let zone = zone.strip_suffix('.').unwrap_or(zone);
+    if zone.is_empty() {
+        return Err("the zone must not be the root zone".to_string());
+    }
+    messages::check_domain_name(zone)?;
+    Ok(zone.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zones_are_checked() {
+        assert_eq!(parse_zone("chat.example"), Ok("chat.example".into()));
+        assert_eq!(parse_zone("chat.example."), Ok("chat.example".into()));
+        assert!(parse_zone("chat.example..").is_err());
+        assert!(parse_zone("").is_err());
+        assert!(parse_zone(".").is_err());
+        assert!(parse_zone("chat..example").is_err());
+        assert!(parse_zone(&format!("{}.example", "a".repeat(64))).is_err());
+        assert!(parse_zone(&"a.".repeat(127)).is_ok());
+        assert!(parse_zone(&"a.".repeat(128)).is_err());
+    }
 }
@@ -0,0 +1,410 @@
+//! A relay server for multi-user chat rooms.
+//!
+//! The relay acts as authoritative name server for a chat zone. Every room is a subdomain of the zone and keeps a log of its messages, numbered consecutively.
+//! Clients post to a room by carrying their messages upstream in query names below `<room>.<zone>` (see `transport::upstream`), which keeps the messages of different clients apart by their session, and read it by polling `<cursor>.<room>.<zone>`, where the cursor is the number of the first message they have not seen yet.
+//! Each client keeps track of its own cursor, so the relay does not need to recognize clients whose queries arrive through changing resolvers.
+//! Payloads are stored as they were posted, so the relay never needs to read messages that are encrypted (see `transport::crypto`).
+
+use crate::dns::edns::Edns;
+use crate::dns::messages::{self, DNSMessage, MAX_LABEL_LENGTH};
+use crate::dns::types::RecordData;
+use crate::transport::upstream::{Fragment, Reassembler};
+use crate::transport::{self, base32, Handler, Medium, Transport, EDNS_PAYLOAD_SIZE};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// The number of messages a room keeps for clients catching up.
+const ROOM_HISTORY: usize = 1000;
+
+/// The number of rooms a relay keeps, posts to further rooms are dropped.
+const MAX_ROOMS: usize = 100;
+
+/// Serves the rooms below `zone` via `transport` until an error occurs.
+pub async fn run_relay(transport: Arc<dyn Transport>, zone: String) -> io::Result<()> {
+    transport
+        .serve(Arc::new(Mutex::new(Relay::new(zone))))
+        .await
+}
+
+/// Checks that `room` can be used as room of the relay for `zone`, i.e. that it is a single label and that queries for it are valid names.
+pub fn check_room(room: &str, zone: &str) -> Result<(), String> {
+    if room.is_empty() || room.contains('.') {
+        return Err(format!("{} is not a single label", room));
+    }
+    if room.len() > MAX_LABEL_LENGTH {
+        return Err(format!(
+            "{} is longer than {} bytes",
+            room, MAX_LABEL_LENGTH
+        ));
+    }
+    messages::check_domain_name(&format!("{}.{}.{}", u32::MAX, room, zone))
+}
+
+/// A single chat room.
+#[derive(Default)]
+struct Room {
+    /// Number of the oldest message still kept
+    first: u32,
+    /// Payloads of the most recent messages, oldest first
+    messages: VecDeque<Vec<u8>>,
+    /// Messages currently being posted
+    reassembler: Reassembler,
+}
+
+impl Room {
+    /// Appends a message to the log, dropping the oldest one if the room is full.
+    fn post(&mut self, payload: Vec<u8>) {
+        self.messages.push_back(payload);
+        if self.messages.len() > ROOM_HISTORY {
+            self.messages.pop_front();
+            self.first += 1;
+        }
+    }
+
+    /// All messages starting at `cursor`, along with their numbers.
+    fn since(&self, cursor: u32) -> impl Iterator<Item = (u32, &Vec<u8>)> {
+        let skip = cursor.saturating_sub(self.first) as usize;
+        (self.first..).zip(self.messages.iter()).skip(skip)
+    }
+}
+
+/// The kinds of queries a relay understands.
+#[derive(Debug, PartialEq)]
+enum Query {
+    /// Asks for the messages of `room` starting at `cursor`
+    Poll { room: String, cursor: u32 },
+    /// Carries a part of a message posted to `room`
+    Post { room: String, fragment: Fragment },
+}
+
+impl Query {
+    /// Interprets a query name below `zone`.
+    fn from_name(name: &str, zone: &str) -> Option<Self> {
+        // resolvers may alter the case of query names
+        let lowercase = name.to_ascii_lowercase();
+        let zone = zone.to_ascii_lowercase();
+        let mut labels = lowercase
+            .strip_suffix(&zone)?
+            .strip_suffix('.')?
+            .rsplit('.');
+        let room = labels.next()?.to_string();
+
+        match Fragment::from_name(&lowercase, &format!("{}.{}", room, zone)) {
+            Some(fragment) => Some(Query::Post { room, fragment }),
+            None => {
+                let cursor = labels.next()?.parse().ok()?;
+                if labels.next().is_some() {
+                    return None;
+                }
+                Some(Query::Poll { room, cursor })
+            }
+        }
+    }
+}
+
+/// State of the relay: the zone it is responsible for and all rooms in it.
+pub struct Relay {
+    zone: String,
+    rooms: HashMap<String, Room>,
+}
+
+impl Relay {
+    pub fn new(zone: String) -> Self {
+        Self {
+            zone,
+            rooms: HashMap::new(),
+        }
+    }
+
+    /// Processes a request, storing posted messages, and returns the entries requested by a poll.
+    fn handle(&mut self, request: &DNSMessage) -> Vec<RecordData> {
+        let query = match request
+            .questions
+            .first()
+            .and_then(|question| Query::from_name(&question.name, &self.zone))
+        {
+            Some(query) => query,
+            None => return Vec::new(),
+        };
+
+        match query {
+            Query::Post { room, fragment } => {
+                if !self.rooms.contains_key(&room) && self.rooms.len() >= MAX_ROOMS {
+                    eprintln!("[relay] Too many rooms, dropping post to {}", room);
+                    return Vec::new();
+                }
+                let room = self.rooms.entry(room).or_default();
+                if let Some(payload) = room.reassembler.add(fragment) {
+                    room.post(payload);
+                }
+                Vec::new()
+            }
+            Query::Poll { room, cursor } => match self.rooms.get(&room) {
+                Some(room) => room
+                    .since(cursor)
+                    .map(|(number, payload)| encode_entry(number, payload))
+                    .collect(),
+                None => Vec::new(),
+            },
+        }
+    }
+
+    /// Builds the reply to a request received via UDP, containing at most one entry.
+    ///
+    /// Entries are never split, so if the next one exceeds the negotiated payload size, the TC bit is set to make the client retry via TCP.
+    fn udp_reply(&mut self, request: &DNSMessage) -> DNSMessage {
+        let size_limit = Edns::negotiate(request.edns().as_ref(), EDNS_PAYLOAD_SIZE) as usize;
+        let mut reply = transport::reply_template(request);
+
+        if let Some(entry) = self.handle(request).into_iter().next() {
+            let mut answered = reply.clone();
+            answered.add_answer(entry);
+            if Vec::<u8>::from(answered.clone()).len() <= size_limit {
+                reply = answered;
+            } else {
+                reply.header.is_truncated = true;
+            }
+        }
+
+        reply
+    }
+
+    /// Builds the replies to a request received via TCP, one per entry.
+    fn tcp_replies(&mut self, request: &DNSMessage) -> Vec<DNSMessage> {
+        let template = transport::reply_template(request);
+        let mut replies: Vec<DNSMessage> = self
+            .handle(request)
+            .into_iter()
+            .map(|entry| {
+                let mut reply = template.clone();
+                reply.add_answer(entry);
+                reply
+            })
+            .collect();
+        if replies.is_empty() {
+            replies.push(template);
+        }
+        replies
+    }
+}
+
+impl Handler for Mutex<Relay> {
+    fn handle(&self, request: &DNSMessage, medium: Medium) -> Vec<DNSMessage> {
+        let mut relay = self.lock().unwrap();
+        match medium {
+            Medium::Datagram => vec![relay.udp_reply(request)],
+            Medium::Stream => relay.tcp_replies(request),
+        }
+    }
+}
+
+/// Encodes the payload of a message of a room as TXT data, preceded by its number.
+///
+/// The payload is base32 encoded, since it may be binary (see `Codec::encode_payload`).
+pub fn encode_entry(number: u32, payload: &[u8]) -> RecordData {
+    let mut strings = vec![number.to_string().into_bytes()];
+    strings.extend(
+        base32::encode(payload)
+            .as_bytes()
+            .chunks(255)
+            .map(<[u8]>::to_vec),
+    );
+    RecordData::Txt(strings)
+}
+
+/// Decodes the payload of a message of a room and its number from TXT data.
+pub fn decode_entry(record: &RecordData) -> Option<(u32, Vec<u8>)> {
+    match record {
+        RecordData::Txt(strings) => {
+            let (number, strings) = strings.split_first()?;
+            let number = std::str::from_utf8(number).ok()?.parse().ok()?;
+            let encoded = String::from_utf8(strings.concat()).ok()?;
+            Some((number, base32::decode(&encoded)?))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::crypto::{self, Cipher, PublicKey};
+    use crate::transport::loopback::next_message;
+    use crate::transport::network::{Tcp, Udp};
+    use crate::transport::{parts, receiver, upstream, ChatMessage, Codec};
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio::time;
+
+    fn post(relay: &mut Relay, room: &str, text: &str, id: u16) {
+        let part = parts::split(&ChatMessage::new(text.into()), usize::MAX).remove(0);
+        let zone = format!("{}.chat.example", room);
+        for name in upstream::encode_message(&part, 1, id, &zone, &Codec::default()) {
+            relay.handle(&DNSMessage::new_request(1, name));
+        }
+    }
+
+    fn poll(relay: &mut Relay, room: &str, cursor: u32) -> Vec<(u32, String)> {
+        let request = DNSMessage::new_request(1, format!("{}.{}.chat.example", cursor, room));
+        relay
+            .handle(&request)
+            .iter()
+            .map(|entry| {
+                let (number, payload) = decode_entry(entry).unwrap();
+                let part = Codec::default().decode_payload(&payload).unwrap();
+                let msg = ChatMessage::decode_data(part.id, &part.data, part.sent).unwrap();
+                (number, msg.text)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn query_names() {
+        assert_eq!(
+            Query::from_name("12.Lobby.chat.example", "chat.example"),
+            Some(Query::Poll {
+                room: "lobby".into(),
+                cursor: 12
+            })
+        );
+        assert_eq!(Query::from_name("lobby.chat.example", "chat.example"), None);
+        assert_eq!(
+            Query::from_name("1.2.lobby.chat.example", "chat.example"),
+            None
+        );
+        assert_eq!(
+            Query::from_name("1.lobby.example.org", "chat.example"),
+            None
+        );
+    }
+
+    #[test]
+    fn rooms_are_separate() {
+        let mut relay = Relay::new("chat.example".into());
+        post(&mut relay, "lobby", "hello", 1);
+        post(&mut relay, "lobby", "world", 2);
+        post(&mut relay, "other", "elsewhere", 3);
+
+        assert_eq!(
+            poll(&mut relay, "lobby", 0),
+            vec![(0, "hello".into()), (1, "world".into())]
+        );
+        assert_eq!(poll(&mut relay, "lobby", 1), vec![(1, "world".into())]);
+        assert_eq!(poll(&mut relay, "lobby", 2), vec![]);
+        assert_eq!(poll(&mut relay, "other", 0), vec![(0, "elsewhere".into())]);
+        assert_eq!(poll(&mut relay, "empty", 0), vec![]);
+    }
+
+    #[test]
+    fn members_posting_the_same_id_are_kept_apart() {
+        let mut relay = Relay::new("chat.example".into());
+        let codec = Codec::default();
+        let names: Vec<Vec<String>> = ["hello from alice", "hello from bob!"]
+            .iter()
+            .enumerate()
+            .map(|(session, text)| {
+                let part = parts::split(&ChatMessage::new(text.repeat(20)), usize::MAX).remove(0);
+                upstream::encode_message(&part, session as u32, 0, "lobby.chat.example", &codec)
+            })
+            .collect();
+        assert!(names[0].len() > 1 && names[0].len() == names[1].len());
+        for (alice, bob) in names[0].iter().zip(names[1].iter()) {
+            relay.handle(&DNSMessage::new_request(1, alice.clone()));
+            relay.handle(&DNSMessage::new_request(1, bob.clone()));
+        }
+
+        assert_eq!(
+            poll(&mut relay, "lobby", 0),
+            vec![
+                (0, "hello from alice".repeat(20)),
+                (1, "hello from bob!".repeat(20))
+            ]
+        );
+    }
+
+    #[test]
+    fn rooms_are_limited() {
+        let mut relay = Relay::new("chat.example".into());
+        for i in 0..=MAX_ROOMS {
+            post(&mut relay, &format!("room{}", i), "hello", 1);
+        }
+        assert_eq!(relay.rooms.len(), MAX_ROOMS);
+        assert_eq!(poll(&mut relay, &format!("room{}", MAX_ROOMS), 0), vec![]);
+
+        // but existing rooms keep working
+        post(&mut relay, "room0", "world", 2);
+        assert_eq!(poll(&mut relay, "room0", 1), vec![(1, "world".into())]);
+    }
+
+    #[test]
+    fn room_names_are_checked() {
+        assert_eq!(check_room("lobby", "chat.example"), Ok(()));
+        assert!(check_room("", "chat.example").is_err());
+        assert!(check_room("lobby.annex", "chat.example").is_err());
+        assert!(check_room(&"a".repeat(64), "chat.example").is_err());
+        assert!(check_room(&"a".repeat(63), &"b.".repeat(100)).is_err());
+    }
+
+    #[test]
+    fn old_messages_are_dropped() {
+        let mut relay = Relay::new("chat.example".into());
+        for i in 0..=ROOM_HISTORY {
+            post(&mut relay, "lobby", &i.to_string(), i as u16);
+        }
+
+        let entries = poll(&mut relay, "lobby", 0);
+        assert_eq!(entries.len(), ROOM_HISTORY);
+        assert_eq!(entries[0], (1, "1".into()));
+    }
+
+    #[tokio::test]
+    async fn clients_chat_through_relay_on_localhost() {
+        // pick a free port, which the relay binds via UDP and TCP again
+        let port = TcpListener::bind(("127.0.0.1", 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        tokio::spawn(run_relay(Arc::new(Udp::new(port)), "chat.example".into()));
+
+        // members of the room share a key pair, which the relay does not know
+        let secret = crypto::generate_key();
+        let codec = Codec::new(Cipher::new(&secret, &PublicKey::from(&secret)));
+
+        let mut clients = Vec::new();
+        let transports: [Arc<dyn Transport>; 2] = [
+            Arc::new(Udp::new(0).with_target("127.0.0.1".into(), port)),
+            Arc::new(Tcp::new(0).with_target("127.0.0.1".into(), port)),
+        ];
+        for transport in transports.iter() {
+            let (outgoing, rx) = mpsc::unbounded_channel();
+            let (sx, incoming) = mpsc::unbounded_channel();
+            tokio::spawn(receiver::poll_messages(
+                sx,
+                transport.clone(),
+                "chat.example".into(),
+                Some("lobby".into()),
+                Some(rx),
+                codec.clone(),
+            ));
+            clients.push((outgoing, incoming));
+        }
+
+        let msg = ChatMessage::new("hello from the other side".into());
+        clients[0].0.send(msg.clone()).unwrap();
+
+        // the message reaches the other client, but is not echoed back to the author
+        let received = time::timeout(Duration::from_secs(10), next_message(&mut clients[1].1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.text, msg.text);
+        assert!(
+            time::timeout(Duration::from_secs(6), next_message(&mut clients[0].1))
+                .await
+                .is_err()
+        );
+    }
+}
@@ -1,20 +1,83 @@
-use crate::transport::ChatMessage;
+use crate::commands::{self, Command, Input};
+use crate::history::History;
+use crate::transport::{Activity, ChatMessage, Progress};
+use chrono::{DateTime, Local};
+use std::collections::BTreeMap;
+use std::fs;
 use std::iter::FromIterator;
+use std::path::PathBuf;
+use std::time::Duration;
 
 /// Application State
 ///
-/// Stores all sent and received messages.
+/// Stores all sent and received messages, appending them to the history if there is one.
 #[derive(Default)]
 pub struct State {
+    /// Messages along with their direction, attachments stripped of their data
     pub messages: Vec<(ChatMessage, MessageType)>,
     pub input: Vec<char>,
     pub cursor_pos: usize,
+    /// Messages split into several parts that are still being transferred, by direction and id
+    pub transfers: BTreeMap<(bool, u32), Progress>,
+    /// A note for the user, e.g. why the history could not be saved
+    pub notice: Option<String>,
+    /// Where messages are kept between sessions
+    history: Option<History>,
+    /// First line shown in the message pane, `None` while following new messages
+    pub scroll: Option<usize>,
+    /// Number of messages received while scrolled up
+    pub unseen: usize,
+    /// Height of the message pane and number of lines of all messages, as of the last draw
+    pub pane: (usize, usize),
+    /// What the transport reported about the connection to the peer
+    pub connection: Connection,
+    /// The name our own messages are sent with, set by `--nick` or `/nick`
+    pub nick: Option<String>,
 }
 
-#[derive(Clone)]
+/// What the user interface has to do after the input was submitted.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    None,
+    Send(ChatMessage),
+    /// Sends the file at the path, which is left to the frontend as reading it may take a while
+    SendFile(PathBuf),
+    Connect {
+        host: String,
+        port: u16,
+    },
+    Quit,
+}
+
+/// What is known about the connection to the peer.
+#[derive(Clone, Default)]
+pub struct Connection {
+    /// When the peer was last heard from
+    pub last_contact: Option<DateTime<Local>>,
+    /// How long the peer took to answer the last query, if we are the one polling
+    pub latency: Option<Duration>,
+    /// Why the peer could not be reached, cleared once it is heard from again
+    pub error: Option<String>,
+    /// Number of messages or parts of them not delivered yet
+    pub queue: usize,
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+}
+
+/// Direction of a message and, for our own messages, whether the peer received it.
+#[derive(Clone, Debug, PartialEq)]
 pub enum MessageType {
+    /// Sent in an earlier session, so whether it was delivered is not known
     Sent,
+    /// Waiting for the peer to fetch it
+    Queued,
+    /// Received by the peer
+    Delivered,
+    /// Could not be delivered so far, delivery is retried
+    Failed,
     Received,
+    /// Output of a command, never sent or kept in the history
+    System,
 }
 
 /// Direction of cursor movement.
@@ -31,8 +94,136 @@ impl State {
         Self::default()
     }
 
+    /// Constructs a state continuing the conversation stored in `history`, whose recent `messages` are shown.
+    pub fn with_history(history: History, messages: Vec<(ChatMessage, MessageType)>) -> Self {
+        Self {
+            messages,
+            history: Some(history),
+            ..Self::default()
+        }
+    }
+
     pub fn add_received(&mut self, msg: ChatMessage) {
-        self.messages.push((msg, MessageType::Received));
+        self.record(msg, MessageType::Received);
+    }
+
+    /// Shows a line of output of a command.
+    pub fn add_system(&mut self, text: String) {
+        self.messages
+            .push((ChatMessage::new(text), MessageType::System));
+        self.scroll_to_bottom();
+    }
+
+    /// Stores a message, appending it to the history.
+    ///
+    /// Sending a message scrolls down to it.
+    fn record(&mut self, msg: ChatMessage, ty: MessageType) {
+        match ty {
+            MessageType::Received if self.scroll.is_some() => self.unseen += 1,
+            MessageType::Received | MessageType::System => (),
+            _ => self.scroll_to_bottom(),
+        }
+        let msg = without_data(msg);
+        if let Some(history) = &mut self.history {
+            if let Err(e) = history.append(&msg, &ty) {
+                self.notice = Some(format!("could not save history: {}", e));
+            }
+        }
+        self.messages.push((msg, ty));
+    }
+
+    /// Stores a message about to be sent, signed with our nickname, and returns it.
+    pub fn send(&mut self, mut msg: ChatMessage) -> ChatMessage {
+        msg.nick = self.nick.clone();
+        self.record(msg.clone(), MessageType::Queued);
+        msg
+    }
+
+    /// Records the progress of a transfer, forgetting it once it is complete.
+    pub fn update_progress(&mut self, progress: Progress) {
+        let key = (progress.outgoing, progress.id);
+        if progress.done < progress.total {
+            self.transfers.insert(key, progress);
+        } else {
+            self.transfers.remove(&key);
+        }
+    }
+
+    /// The first line shown when scrolled down all the way.
+    fn bottom(&self) -> usize {
+        let (height, lines) = self.pane;
+        lines.saturating_sub(height)
+    }
+
+    /// Scrolls the message pane up by `lines`.
+    pub fn scroll_up(&mut self, lines: usize) {
+        let top = self.scroll.unwrap_or_else(|| self.bottom());
+        self.scroll_to(top.saturating_sub(lines));
+    }
+
+    /// Scrolls the message pane down by `lines`, following new messages again once at the bottom.
+    pub fn scroll_down(&mut self, lines: usize) {
+        let top = self.scroll.unwrap_or_else(|| self.bottom());
+        self.scroll_to(top.saturating_add(lines));
+    }
+
+    /// Scrolls a page of the message pane up, keeping a line for context.
+    pub fn page_up(&mut self) {
+        self.scroll_up(self.pane.0.saturating_sub(1).max(1));
+    }
+
+    /// Scrolls a page of the message pane down, keeping a line for context.
+    pub fn page_down(&mut self) {
+        self.scroll_down(self.pane.0.saturating_sub(1).max(1));
+    }
+
+    pub fn scroll_to_top(&mut self) {
+        self.scroll_to(0);
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.scroll = None;
+        self.unseen = 0;
+    }
+
+    fn scroll_to(&mut self, top: usize) {
+        if top >= self.bottom() {
+            self.scroll_to_bottom();
+        } else {
+            self.scroll = Some(top);
+        }
+    }
+
+    /// Updates the delivery state of the message with the given id, unless it was delivered already.
+    pub fn update_delivery(&mut self, id: u32, ty: MessageType) {
+        let message = self.messages.iter_mut().rev().find(|(msg, ty)| {
+            msg.id == id && matches!(ty, MessageType::Queued | MessageType::Failed)
+        });
+        if let Some((_, current)) = message {
+            *current = ty;
+        }
+    }
+
+    /// Updates what is known about the connection.
+    pub fn record_activity(&mut self, activity: Activity) {
+        let connection = &mut self.connection;
+        match activity {
+            Activity::Answered { latency } => {
+                connection.last_contact = Some(Local::now());
+                connection.latency = Some(latency);
+                connection.error = None;
+            }
+            Activity::Queried => {
+                connection.last_contact = Some(Local::now());
+                connection.error = None;
+            }
+            Activity::Unreachable(error) => connection.error = Some(error),
+            Activity::Traffic { sent, received } => {
+                connection.bytes_sent += sent;
+                connection.bytes_received += received;
+            }
+            Activity::Queue(queue) => connection.queue = queue,
+        }
     }
 
     /// Add a character to the input string at the current cursor position.
@@ -41,20 +232,81 @@ impl State {
         self.cursor_pos += 1;
     }
 
-    /// Bundle the input string into a message, add it to the internal storage and return it for
-    /// sending.
-    pub fn generate_msg(&mut self) -> Vec<ChatMessage> {
-        let messages = ChatMessage::from_str(String::from_iter(self.input.iter()));
-        self.messages.extend(
-            messages
-                .clone()
-                .into_iter()
-                .zip(std::iter::repeat(MessageType::Sent)),
-        );
-        self.input.clear();
-        self.cursor_pos = 0;
+    /// Handles the input string, which is either a message or a command (see `commands`), and returns what is left to do for the user interface.
+    ///
+    /// Messages are added to the internal storage. If a command fails, the reason is shown and the input is kept for correcting it.
+    pub fn submit(&mut self) -> Action {
+        let input = String::from_iter(self.input.iter());
+        let action = match commands::parse(&input) {
+            Ok(Input::Message(text)) => Ok(Action::Send(ChatMessage::new(text))),
+            Ok(Input::Command(command)) => self.execute(command),
+            Err(e) => Err(e),
+        };
 
-        messages
+        match action {
+            Ok(mut action) => {
+                self.input.clear();
+                self.cursor_pos = 0;
+                self.notice = None;
+                if let Action::Send(message) = action {
+                    action = Action::Send(self.send(message));
+                }
+                action
+            }
+            Err(e) => {
+                self.add_system(e);
+                Action::None
+            }
+        }
+    }
+
+    /// Executes a command, doing as much as possible without the user interface.
+    fn execute(&mut self, command: Command) -> Result<Action, String> {
+        match command {
+            Command::Help => {
+                for usage in commands::HELP {
+                    self.add_system(usage.to_string());
+                }
+            }
+            Command::Nick(nick) => {
+                self.add_system(format!("you are now known as {}", nick));
+                self.nick = Some(nick);
+            }
+            Command::Connect { host, port } => return Ok(Action::Connect { host, port }),
+            Command::Clear => {
+                self.messages.clear();
+                self.scroll_to_bottom();
+            }
+            Command::Save(path) => {
+                let path = path.unwrap_or_else(|| {
+                    PathBuf::from(Local::now().format("kakure-%Y%m%d-%H%M%S.txt").to_string())
+                });
+                fs::write(&path, self.transcript())
+                    .map_err(|e| format!("could not save {}: {}", path.display(), e))?;
+                self.add_system(format!("saved the conversation to {}", path.display()));
+            }
+            Command::Me(text) => {
+                let mut message = ChatMessage::new(text);
+                message.action = true;
+                return Ok(Action::Send(message));
+            }
+            Command::Send(path) => return Ok(Action::SendFile(path)),
+            Command::Quit => return Ok(Action::Quit),
+        }
+        Ok(Action::None)
+    }
+
+    /// The messages shown, as plain text.
+    pub fn transcript(&self) -> String {
+        let mut transcript = String::new();
+        for (msg, ty) in &self.messages {
+            if *ty == MessageType::System {
+                continue;
+            }
+            transcript.push_str(&plain_text(msg, ty));
+            transcript.push('\n');
+        }
+        transcript
     }
 
     /// Moves the cursor
@@ -94,3 +346,114 @@ impl State {
         }
     }
 }
+
+/// Drops the data of an attachment, which is not needed anymore once it was sent or saved.
+fn without_data(mut msg: ChatMessage) -> ChatMessage {
+    if let Some(attachment) = &mut msg.attachment {
+        attachment.data = Vec::new();
+    }
+    msg
+}
+
+/// The name a message is shown with, i.e. the nickname of its author or who wrote it if they did not choose one.
+pub fn author<'a>(msg: &'a ChatMessage, ty: &MessageType) -> &'a str {
+    match (&msg.nick, ty) {
+        (Some(nick), _) => nick,
+        (None, MessageType::Received) => "Them",
+        (None, _) => "You",
+    }
+}
+
+/// A message as a single line of plain text, like in saved conversations.
+pub fn plain_text(msg: &ChatMessage, ty: &MessageType) -> String {
+    let name = author(msg, ty);
+    let time = msg.sent.format("%Y-%m-%d %H:%M");
+    let text = match &msg.attachment {
+        Some(attachment) => format!("[file: {}] {}", attachment.name, msg.text),
+        None => msg.text.clone(),
+    };
+    match msg.action {
+        true => format!("[{}] * {} {}", time, name, text),
+        false => format!("[{}] <{}> {}", time, name, text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrolling_follows_new_messages_at_the_bottom() {
+        let mut state = State::new();
+        state.pane = (10, 30);
+
+        state.page_up();
+        assert_eq!(state.scroll, Some(11));
+        state.add_received(ChatMessage::new("hello".into()));
+        state.add_received(ChatMessage::new("world".into()));
+        assert_eq!(state.unseen, 2);
+
+        state.scroll_to_top();
+        assert_eq!(state.scroll, Some(0));
+        state.scroll_down(25);
+        assert_eq!((state.scroll, state.unseen), (None, 0));
+
+        state.add_received(ChatMessage::new("again".into()));
+        assert_eq!(state.unseen, 0);
+        state.scroll_up(3);
+        "reply".chars().for_each(|c| state.add_input_char(c));
+        assert!(matches!(state.submit(), Action::Send(_)));
+        assert_eq!(state.scroll, None);
+    }
+
+    #[test]
+    fn delivery_is_tracked() {
+        let mut state = State::new();
+        "hello".chars().for_each(|c| state.add_input_char(c));
+        let msg = match state.submit() {
+            Action::Send(msg) => msg,
+            action => panic!("unexpected {:?}", action),
+        };
+        assert_eq!(state.messages[0].1, MessageType::Queued);
+
+        state.update_delivery(msg.id, MessageType::Failed);
+        assert_eq!(state.messages[0].1, MessageType::Failed);
+        state.update_delivery(msg.id, MessageType::Delivered);
+        state.update_delivery(msg.id, MessageType::Failed);
+        assert_eq!(state.messages[0].1, MessageType::Delivered);
+    }
+
+    #[test]
+    fn commands_are_not_sent() {
+        let mut state = State::new();
+        let mut submit = |input: &str| {
+            input.chars().for_each(|c| state.add_input_char(c));
+            state.submit()
+        };
+
+        assert_eq!(submit("/nick alice"), Action::None);
+        assert_eq!(submit("/quit"), Action::Quit);
+        match submit("/me waves") {
+            Action::Send(msg) => {
+                assert!(msg.action && msg.text == "waves");
+                assert_eq!(msg.nick.as_deref(), Some("alice"));
+            }
+            action => panic!("unexpected {:?}", action),
+        }
+        // failed commands are kept for correcting them
+        assert_eq!(submit("/bogus"), Action::None);
+        assert_eq!(String::from_iter(state.input.iter()), "/bogus");
+
+        let shown: Vec<_> = state.messages.iter().map(|(_, ty)| ty.clone()).collect();
+        assert_eq!(
+            shown,
+            vec![
+                MessageType::System,
+                MessageType::Queued,
+                MessageType::System
+            ]
+        );
+        assert_eq!(state.nick.as_deref(), Some("alice"));
+        assert!(state.transcript().ends_with("* alice waves\n"));
+    }
+}
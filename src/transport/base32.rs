@@ -0,0 +1,78 @@
+//! Base32 encoding as defined in [RFC 4648, 6](https://tools.ietf.org/html/rfc4648#section-6), using lower case letters and no padding.
+//!
+//! Domain names are case-insensitive and resolvers may even randomize the case of a query name, so this alphabet is suited for carrying binary data in labels. Decoding accepts both cases.
+
+const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Encodes `data` into a base32 string without padding.
+pub fn encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity((data.len() * 8).div_ceil(5));
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+
+    for &byte in data {
+        buffer = (buffer << 8) | u16::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+
+    encoded
+}
+
+/// Decodes a base32 string without padding. Returns `None` if `data` contains characters outside the alphabet.
+pub fn decode(data: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(data.len() * 5 / 8);
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+
+    for c in data.bytes() {
+        let value = match c.to_ascii_lowercase() {
+            c @ b'a'..=b'z' => c - b'a',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | u16::from(value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc_4648_test_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "my"),
+            ("fo", "mzxq"),
+            ("foo", "mzxw6"),
+            ("foob", "mzxw6yq"),
+            ("fooba", "mzxw6ytb"),
+            ("foobar", "mzxw6ytboi"),
+        ];
+
+        for (plain, encoded) in vectors.iter() {
+            assert_eq!(encode(plain.as_bytes()), *encoded);
+            assert_eq!(decode(encoded).unwrap(), plain.as_bytes());
+            assert_eq!(decode(&encoded.to_uppercase()).unwrap(), plain.as_bytes());
+        }
+    }
+
+    #[test]
+    fn invalid_characters_are_rejected() {
+        assert_eq!(decode("mzx1"), None);
+    }
+}
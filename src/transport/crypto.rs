@@ -0,0 +1,204 @@
+//! End-to-end encryption of chat payloads.
+//!
+//! Every peer holds a static X25519 key pair. Public keys are exchanged and verified out of band, so the shared key of two peers is derived from the Diffie-Hellman secret of their keys via HKDF-SHA256.
+//! Payloads are encrypted with ChaCha20-Poly1305 under a random nonce that is sent along with them, so each part of a split message can be decrypted on its own.
+//! The public key of the sending peer is authenticated as associated data, which keeps messages from being reflected back to their author, along with the context a payload is sent in, like its sequence number (see `transport::reliable`).
+
+use super::base32;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+pub use x25519_dalek::{PublicKey, StaticSecret as SecretKey};
+
+/// Length of the random nonce preceding every encrypted payload.
+const NONCE_LENGTH: usize = 12;
+
+/// Length of the authentication tag following every encrypted payload.
+const TAG_LENGTH: usize = 16;
+
+/// The number of bytes encryption adds to a payload.
+pub const OVERHEAD: usize = NONCE_LENGTH + TAG_LENGTH;
+
+/// Binds derived keys to this application.
+const KDF_INFO: &[u8] = b"kakure chat payload v1";
+
+/// Generates a new secret key.
+pub fn generate_key() -> SecretKey {
+    SecretKey::random_from_rng(OsRng)
+}
+
+/// Formats a key for storing or sharing it.
+pub fn format_key(key: &[u8; 32]) -> String {
+    base32::encode(key)
+}
+
+/// Parses a key formatted with `format_key`.
+pub fn parse_key(key: &str) -> Option<[u8; 32]> {
+    let bytes = base32::decode(key.trim())?;
+    <[u8; 32]>::try_from(bytes.as_slice()).ok()
+}
+
+/// Reads a secret key from the file at `path`.
+pub fn load_secret_key<P: AsRef<Path>>(path: P) -> io::Result<SecretKey> {
+    let contents = fs::read_to_string(path)?;
+    parse_key(&contents)
+        .map(SecretKey::from)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed secret key"))
+}
+
+/// Writes a secret key to a new file at `path`, readable only by its owner.
+pub fn save_secret_key<P: AsRef<Path>>(path: P, key: &SecretKey) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", format_key(&key.to_bytes()))
+}
+
+/// Encrypts and decrypts the payloads exchanged with a single peer.
+#[derive(Clone)]
+pub struct Cipher {
+    aead: ChaCha20Poly1305,
+    /// Our public key, authenticated along with the payloads we send
+    own: [u8; 32],
+    /// The peer's public key, authenticated along with the payloads we receive
+    peer: [u8; 32],
+}
+
+impl Cipher {
+    /// Derives the key shared with the owner of `peer`.
+    ///
+    /// Returns `None` if `peer` is not a valid public key, i.e. one that would result in a predictable shared key.
+    pub fn new(secret: &SecretKey, peer: &PublicKey) -> Option<Self> {
+        let shared = secret.diffie_hellman(peer);
+        if !shared.was_contributory() {
+            return None;
+        }
+
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(KDF_INFO, &mut key)
+            .expect("32 bytes are a valid HKDF output length");
+
+        Some(Self {
+            aead: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            own: PublicKey::from(secret).to_bytes(),
+            peer: peer.to_bytes(),
+        })
+    }
+
+    /// Encrypts `plaintext`, returning the nonce followed by the ciphertext.
+    ///
+    /// `context` is authenticated, but not encrypted, so the payload can only be opened in the same context.
+    pub fn seal(&self, plaintext: &[u8], context: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; NONCE_LENGTH];
+        OsRng.fill_bytes(&mut nonce);
+
+        let ciphertext = self
+            .aead
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &[&self.own[..], context].concat(),
+                },
+            )
+            .expect("payloads are far below the ChaCha20-Poly1305 size limit");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        sealed
+    }
+
+    /// Decrypts a payload produced by the peer's `seal` in `context`. Returns `None` if it was not, or if it was tampered with.
+    pub fn open(&self, sealed: &[u8], context: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < OVERHEAD {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+
+        self.aead
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &[&self.peer[..], context].concat(),
+                },
+            )
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (Cipher, Cipher) {
+        let alice = generate_key();
+        let bob = generate_key();
+        (
+            Cipher::new(&alice, &PublicKey::from(&bob)).unwrap(),
+            Cipher::new(&bob, &PublicKey::from(&alice)).unwrap(),
+        )
+    }
+
+    #[test]
+    fn peers_share_a_key() {
+        let (alice, bob) = pair();
+
+        let sealed = alice.seal(b"hello", b"");
+        assert_eq!(sealed.len(), 5 + OVERHEAD);
+        assert_eq!(bob.open(&sealed, b"").unwrap(), b"hello");
+        // nonces are never reused
+        assert_ne!(alice.seal(b"hello", b""), sealed);
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let (alice, bob) = pair();
+
+        let mut sealed = alice.seal(b"hello", b"");
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert_eq!(bob.open(&sealed, b""), None);
+        assert_eq!(bob.open(&sealed[..OVERHEAD - 1], b""), None);
+    }
+
+    #[test]
+    fn context_is_authenticated() {
+        let (alice, bob) = pair();
+        let sealed = alice.seal(b"hello", b"1:2");
+        assert_eq!(bob.open(&sealed, b"1:2").unwrap(), b"hello");
+        assert_eq!(bob.open(&sealed, b"1:3"), None);
+    }
+
+    #[test]
+    fn messages_are_not_reflected() {
+        let (alice, _) = pair();
+        assert_eq!(alice.open(&alice.seal(b"hello", b""), b""), None);
+    }
+
+    #[test]
+    fn keys_round_trip() {
+        let key = generate_key();
+        let parsed = parse_key(&format_key(&key.to_bytes())).unwrap();
+        assert_eq!(parsed, key.to_bytes());
+        assert_eq!(parse_key("mzxw6"), None);
+    }
+
+    #[test]
+    fn weak_keys_are_rejected() {
+        assert!(Cipher::new(&generate_key(), &PublicKey::from([0; 32])).is_none());
+    }
+}
@@ -0,0 +1,223 @@
+//! The versioned format of payloads on the wire.
+//!
+//! A payload carries a single part of a message (see `transport::parts`). It starts with the magic bytes `KKR`, a version and a byte of flags, followed by typed fields.
+//! Every field consists of its type, the length of its value as LEB128 integer and the value itself. Fields may appear in any order, and fields of unknown types are skipped, so new ones can be added without breaking older peers.
+//! Changes older peers cannot cope with bump the version or set a flag instead, both of which make older peers drop the payload.
+//!
+//! Payloads without the magic bytes are in the legacy format used before, i.e. an RFC 3339 timestamp of 25 bytes followed by the text of the whole message.
+
+use super::parts::Part;
+use super::ChatMessage;
+use chrono::{DateTime, Local, TimeZone};
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+
+/// Marks a payload in the versioned format.
+const MAGIC: &[u8] = b"KKR";
+
+/// The version of the format we write and the latest one we can read.
+pub const VERSION: u8 = 1;
+
+/// Flags understood by us. None are defined yet.
+const KNOWN_FLAGS: u8 = 0;
+
+/// Field holding the id of the message as 4 byte integer followed by the index of the part and the number of parts as 2 byte integers.
+const PART_FIELD: u8 = 1;
+
+/// Field holding the time the message was sent, in milliseconds since the Unix epoch as 8 byte integer.
+const SENT_FIELD: u8 = 2;
+
+/// Field holding the data of the part.
+const DATA_FIELD: u8 = 3;
+
+const PART_LENGTH: usize = 8;
+const SENT_LENGTH: usize = 8;
+
+/// Maximum number of bytes of a length, enough for values of up to 256 MiB.
+const MAX_LENGTH_BYTES: usize = 4;
+
+/// Number of bytes a payload takes in addition to its data, assuming less than 2 MiB of data, which is far more than fits into a reply.
+pub const OVERHEAD: usize = MAGIC.len() + 2 + (2 + PART_LENGTH) + (2 + SENT_LENGTH) + (1 + 3);
+
+/// Length of the timestamp preceding the text in the legacy format.
+const LEGACY_TIMESTAMP_LENGTH: usize = 25;
+
+/// Encodes a part into a payload of the current version.
+pub fn encode(part: &Part) -> Vec<u8> {
+    let mut payload = MAGIC.to_vec();
+    payload.push(VERSION);
+    payload.push(0);
+
+    let mut position = part.id.to_be_bytes().to_vec();
+    position.extend_from_slice(&part.index.to_be_bytes());
+    position.extend_from_slice(&part.count.to_be_bytes());
+    push_field(&mut payload, PART_FIELD, &position);
+    push_field(
+        &mut payload,
+        SENT_FIELD,
+        &part.sent.timestamp_millis().to_be_bytes(),
+    );
+    push_field(&mut payload, DATA_FIELD, &part.data);
+    payload
+}
+
+/// Decodes a payload of any version we know, including the legacy format, and rejects malformed ones.
+pub fn decode(payload: &[u8]) -> Option<Part> {
+    let payload = match payload.strip_prefix(MAGIC) {
+        Some(payload) => payload,
+        None => return decode_legacy(payload),
+    };
+    let (version, flags, mut fields) = match payload {
+        [version, flags, fields @ ..] => (*version, *flags, fields),
+        _ => return None,
+    };
+    if version > VERSION {
+        eprintln!(
+            "[transport] Dropping payload of unknown version {}",
+            version
+        );
+        return None;
+    }
+    if flags & !KNOWN_FLAGS != 0 {
+        eprintln!(
+            "[transport] Dropping payload with unknown flags {:#04x}",
+            flags
+        );
+        return None;
+    }
+
+    let (mut position, mut sent, mut data) = (None, None, None);
+    while !fields.is_empty() {
+        let (field, value, rest) = read_field(fields)?;
+        match field {
+            PART_FIELD => position = Some(value),
+            SENT_FIELD => sent = Some(value),
+            DATA_FIELD => data = Some(value),
+            _ => (),
+        }
+        fields = rest;
+    }
+
+    let position: [u8; PART_LENGTH] = position?.try_into().ok()?;
+    let id = u32::from_be_bytes(position[0..4].try_into().ok()?);
+    let index = u16::from_be_bytes(position[4..6].try_into().ok()?);
+    let count = u16::from_be_bytes(position[6..8].try_into().ok()?);
+    let sent = i64::from_be_bytes(sent?.try_into().ok()?);
+    if index >= count {
+        return None;
+    }
+
+    Some(Part {
+        id,
+        index,
+        count,
+        sent: Local.timestamp_millis_opt(sent).single()?,
+        data: data?.to_vec(),
+    })
+}
+
+/// Decodes a payload in the legacy format into the only part of a text message.
+///
+/// The id is derived from the payload, as the format has none, so a payload received twice is still recognized.
+fn decode_legacy(payload: &[u8]) -> Option<Part> {
+    let timestamp = std::str::from_utf8(payload.get(..LEGACY_TIMESTAMP_LENGTH)?).ok()?;
+    let sent = DateTime::parse_from_rfc3339(timestamp).ok()?;
+    let text = std::str::from_utf8(&payload[LEGACY_TIMESTAMP_LENGTH..]).ok()?;
+
+    let digest = Sha256::digest(payload);
+    Some(Part {
+        id: u32::from_be_bytes(digest[..4].try_into().ok()?),
+        index: 0,
+        count: 1,
+        sent: DateTime::from(sent),
+        data: ChatMessage::new(text.to_string()).encode_data(),
+    })
+}
+
+fn push_field(payload: &mut Vec<u8>, field: u8, value: &[u8]) {
+    payload.push(field);
+    let mut length = value.len();
+    while length >= 0x80 {
+        payload.push(length as u8 | 0x80);
+        length >>= 7;
+    }
+    payload.push(length as u8);
+    payload.extend_from_slice(value);
+}
+
+/// Reads a field, returning its type and value along with the remaining fields.
+fn read_field(fields: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (field, rest) = fields.split_first()?;
+    let mut length = 0;
+    for (i, byte) in rest.iter().take(MAX_LENGTH_BYTES).enumerate() {
+        length |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            let rest = &rest[i + 1..];
+            return Some((*field, rest.get(..length)?, &rest[length..]));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::parts;
+    use crate::transport::ChatMessage;
+
+    fn part(length: usize) -> Part {
+        let mut part = parts::split(&ChatMessage::new("a".repeat(length)), usize::MAX).remove(0);
+        // timestamps are sent with millisecond precision
+        part.sent = Local.timestamp_millis(part.sent.timestamp_millis());
+        part
+    }
+
+    #[test]
+    fn parts_round_trip() {
+        for length in [0, 200, 5000].iter() {
+            let part = part(*length);
+            let payload = encode(&part);
+            assert!(payload.len() <= part.data.len() + OVERHEAD);
+            assert_eq!(decode(&payload), Some(part));
+        }
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        let part = part(10);
+        let mut payload = encode(&part);
+        push_field(&mut payload, 200, b"from the future");
+        assert_eq!(decode(&payload), Some(part));
+
+        // but not missing ones
+        assert_eq!(decode(&payload[..MAGIC.len() + 2 + 2 + PART_LENGTH]), None);
+    }
+
+    #[test]
+    fn newer_versions_are_dropped() {
+        let mut payload = encode(&part(10));
+        payload[MAGIC.len()] = VERSION + 1;
+        assert_eq!(decode(&payload), None);
+
+        let mut payload = encode(&part(10));
+        payload[MAGIC.len() + 1] = 0x01;
+        assert_eq!(decode(&payload), None);
+    }
+
+    #[test]
+    fn legacy_payloads_are_decoded() {
+        let payload = b"2020-12-24T18:34:16+01:00hello there";
+        let part = decode(payload).unwrap();
+        assert_eq!((part.index, part.count), (0, 1));
+        assert_eq!(part.sent.timestamp(), 1608831256);
+        let msg = ChatMessage::decode_data(part.id, &part.data, part.sent).unwrap();
+        assert_eq!(msg.text, "hello there");
+        assert_eq!(msg.nick, None);
+        // the same payload gets the same id
+        assert_eq!(decode(payload).unwrap().id, part.id);
+
+        assert_eq!(decode(b"2020-12-24T18:34:16+01:00").unwrap().count, 1);
+        assert_eq!(decode(b"2020-12-24T18:34:16"), None);
+        assert_eq!(decode(b"not a timestamp at all, really"), None);
+    }
+}
@@ -0,0 +1,170 @@
+//! Framing of DNS messages sent via TCP.
+//!
+//! As defined in [RFC 1035, 4.2.2](https://tools.ietf.org/html/rfc1035#section-4.2.2), every message is prefixed with its length as a 2 byte integer, which does not include the prefix itself.
+//! A stream does not preserve the boundaries of writes, so a single read may return part of a frame or several frames at once.
+
+use std::convert::TryFrom;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+
+/// Length of the prefix holding the length of a frame.
+const PREFIX_LENGTH: usize = 2;
+
+/// How long to wait for a peer to send a frame before giving up on the connection.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Writes `msg` as a single frame.
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, msg: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(msg.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "message exceeds the maximum frame length",
+        )
+    })?;
+
+    // write prefix and message at once, so they do not end up in separate segments
+    let mut frame = Vec::with_capacity(PREFIX_LENGTH + msg.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(msg);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// Reads frames from a stream, buffering data that arrived ahead of the frame it belongs to.
+pub struct FrameReader<R> {
+    reader: R,
+    /// Data read from the stream, but not returned as part of a frame yet
+    buffer: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Reads the next complete frame, returning the message without its length prefix.
+    ///
+    /// Returns `None` if the stream ended after the previous frame and an error of kind `UnexpectedEof` if it ended within a frame.
+    /// Fails with an error of kind `TimedOut` if the peer stays silent for longer than `READ_TIMEOUT`.
+    pub async fn read_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut chunk = [0; 4096];
+
+        loop {
+            if let Some(frame) = self.take_frame() {
+                return Ok(Some(frame));
+            }
+
+            let read = time::timeout(READ_TIMEOUT, self.reader.read(&mut chunk));
+            let read_length = match read
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+            {
+                Ok(read_length) => read_length,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if read_length == 0 {
+                return if self.buffer.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream ended within a frame",
+                    ))
+                };
+            }
+            self.buffer.extend_from_slice(&chunk[..read_length]);
+        }
+    }
+
+    /// Removes the first frame from the buffer if it is complete.
+    fn take_frame(&mut self) -> Option<Vec<u8>> {
+        if self.buffer.len() < PREFIX_LENGTH {
+            return None;
+        }
+        let len = u16::from_be_bytes([self.buffer[0], self.buffer[1]]) as usize;
+        if self.buffer.len() < PREFIX_LENGTH + len {
+            return None;
+        }
+
+        let rest = self.buffer.split_off(PREFIX_LENGTH + len);
+        let frame = std::mem::replace(&mut self.buffer, rest);
+        Some(frame[PREFIX_LENGTH..].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    /// Returns the data it was given in reads of at most `step` bytes.
+    struct Trickle {
+        data: Vec<u8>,
+        step: usize,
+    }
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let len = self.step.min(buf.remaining()).min(self.data.len());
+            buf.put_slice(&self.data[..len]);
+            self.data.drain(..len);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    async fn frames(messages: &[&[u8]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for msg in messages {
+            write_frame(&mut data, msg).await.unwrap();
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn frames_are_reassembled() {
+        let long = vec![42; 10_000];
+        let messages: [&[u8]; 3] = [b"first", &long, b""];
+
+        // frames split across reads as well as several frames per read
+        for step in [1, 3, 4096].iter() {
+            let mut reader = FrameReader::new(Trickle {
+                data: frames(&messages).await,
+                step: *step,
+            });
+            for msg in messages.iter() {
+                assert_eq!(reader.read_frame().await.unwrap().unwrap(), *msg);
+            }
+            assert_eq!(reader.read_frame().await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_frames_are_errors() {
+        let mut data = frames(&[b"message"]).await;
+        data.pop();
+
+        let mut reader = FrameReader::new(data.as_slice());
+        let err = reader.read_frame().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_rejected() {
+        let err = write_frame(&mut Vec::new(), &vec![0; 65536])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
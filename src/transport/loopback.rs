@@ -0,0 +1,162 @@
+//! An in-process transport connecting two peers through channels.
+//!
+//! Messages still pass through their wire format, so peers connected this way take the same path as peers connected via the network, which lets tests run several of them without binding any ports.
+
+use super::{ChatMessage, Handler, Medium, Transport, Update};
+use crate::dns::messages::DNSMessage;
+use async_trait::async_trait;
+use std::convert::TryFrom;
+use std::io;
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, Mutex};
+
+/// An encoded request along with the channel to send the encoded replies back on.
+type Exchange = (Vec<u8>, oneshot::Sender<Vec<Vec<u8>>>);
+
+/// One end of a connection between two peers in the same process.
+pub struct Loopback {
+    /// Requests for the other end
+    peer: UnboundedSender<Exchange>,
+    /// Requests from the other end, taken by whoever serves them
+    requests: Mutex<UnboundedReceiver<Exchange>>,
+}
+
+impl Loopback {
+    /// Creates both ends of a connection.
+    pub fn pair() -> (Self, Self) {
+        let (a_sender, a_receiver) = mpsc::unbounded_channel();
+        let (b_sender, b_receiver) = mpsc::unbounded_channel();
+        (
+            Self {
+                peer: b_sender,
+                requests: Mutex::new(a_receiver),
+            },
+            Self {
+                peer: a_sender,
+                requests: Mutex::new(b_receiver),
+            },
+        )
+    }
+}
+
+#[async_trait]
+impl Transport for Loopback {
+    async fn query(&self, request: &DNSMessage) -> io::Result<Vec<DNSMessage>> {
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        // a dropped end behaves like a peer that went offline
+        let unreachable = || io::Error::from(io::ErrorKind::ConnectionRefused);
+        self.peer
+            .send((request.clone().into(), reply_sender))
+            .map_err(|_| unreachable())?;
+        let replies = reply_receiver.await.map_err(|_| unreachable())?;
+
+        let mut received = Vec::new();
+        for reply in replies {
+            match DNSMessage::try_from(reply.as_slice()) {
+                Ok(parsed) if parsed.is_reply_to(request) => received.push(parsed),
+                Ok(_) => eprintln!("[transport] Discarding reply not matching the query"),
+                Err(e) => eprintln!("[transport] Dropping malformed reply: {}", e),
+            }
+        }
+        Ok(received)
+    }
+
+    async fn serve(&self, handler: Arc<dyn Handler>) -> io::Result<()> {
+        let mut requests = self.requests.lock().await;
+        while let Some((request, reply_sender)) = requests.recv().await {
+            let replies = match DNSMessage::try_from(request.as_slice()) {
+                Ok(request) => handler.handle(&request, Medium::Stream),
+                Err(e) => {
+                    eprintln!("[transport] Dropping malformed request: {}", e);
+                    Vec::new()
+                }
+            };
+            // the querying peer may have given up already
+            let _ = reply_sender.send(replies.into_iter().map(Vec::from).collect());
+        }
+        Ok(())
+    }
+}
+
+/// Waits for the next message reported on `updates`, skipping everything else the transport reports.
+pub async fn next_message(updates: &mut UnboundedReceiver<Update>) -> Option<ChatMessage> {
+    loop {
+        if let Update::Message(msg) = updates.recv().await? {
+            return Some(msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Action, MessageType, State};
+    use crate::transport::{receiver, sender, Codec, Update};
+    use std::time::Duration;
+    use tokio::time;
+
+    /// Starts a peer chatting via `transport`, returning the channels its user interface would use.
+    fn spawn_peer(
+        transport: Loopback,
+    ) -> (UnboundedSender<ChatMessage>, UnboundedReceiver<Update>) {
+        let transport: Arc<dyn Transport> = Arc::new(transport);
+        let (outgoing, rx) = mpsc::unbounded_channel();
+        let (sx, incoming) = mpsc::unbounded_channel();
+        tokio::spawn(sender::run_sender(
+            rx,
+            sx.clone(),
+            transport.clone(),
+            "chat.example".into(),
+            Codec::default(),
+        ));
+        tokio::spawn(receiver::poll_messages(
+            sx,
+            transport,
+            "chat.example".into(),
+            None,
+            None,
+            Codec::default(),
+        ));
+        (outgoing, incoming)
+    }
+
+    #[tokio::test]
+    async fn peers_chat_through_loopback() {
+        let (a, b) = Loopback::pair();
+        let (a_outgoing, mut a_incoming) = spawn_peer(a);
+        let (_b_outgoing, mut b_incoming) = spawn_peer(b);
+
+        let mut alice = State::new();
+        "hello bob".chars().for_each(|c| alice.add_input_char(c));
+        match alice.submit() {
+            Action::Send(msg) => a_outgoing.send(msg).unwrap(),
+            action => panic!("unexpected {:?}", action),
+        }
+
+        let mut bob = State::new();
+        let received = time::timeout(Duration::from_secs(10), next_message(&mut b_incoming))
+            .await
+            .unwrap()
+            .unwrap();
+        bob.add_received(received);
+        assert!(matches!(
+            &bob.messages[..],
+            [(msg, MessageType::Received)] if msg.text == "hello bob"
+        ));
+
+        // the acknowledgement reaches alice with bob's next poll
+        let delivered = async {
+            loop {
+                if let Some(Update::Delivered(id)) = a_incoming.recv().await {
+                    return id;
+                }
+            }
+        };
+        let id = time::timeout(Duration::from_secs(10), delivered)
+            .await
+            .unwrap();
+        alice.update_delivery(id, MessageType::Delivered);
+        assert_eq!(alice.messages[0].1, MessageType::Delivered);
+    }
+}
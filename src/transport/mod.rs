@@ -1,151 +1,534 @@
+use crate::dns::edns::Edns;
+use crate::dns::messages::DNSMessage;
 use crate::dns::types::RecordData;
-use crate::dns::{messages::DNSMessage, types::RecordType};
-use chrono::{DateTime, Local, SecondsFormat};
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use crypto::Cipher;
+use parts::Part;
+use sha2::{Digest, Sha256};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
 
+pub mod base32;
+pub mod crypto;
+pub mod envelope;
+pub mod framing;
+#[cfg(test)]
+pub mod loopback;
+pub mod network;
+pub mod parts;
 pub mod receiver;
+pub mod reliable;
 pub mod sender;
+pub mod upstream;
 
-/// The maximum length of a message per DNS message. This is the maximum number of bytes a TXT record can hold minus 25 bytes for the timestamp.
-const MAX_MSG_LENGTH: usize = 65_254;
+/// The UDP payload size advertised in the EDNS record of our queries and replies.
+pub const EDNS_PAYLOAD_SIZE: u16 = 4096;
 
-/// Representation of a single timestamped message
-///
-/// ## Maximum Length
-/// Each message may only be up to 65,535 bytes long due to constraints in the DNS standard which requires that the length of the TXT record section (or any `RDATA` record for that matter) must be expressed by a unsigned 16 bit integer.
-/// Internal formatting additionally requires a length byte every 255 bytes, reducing the effective maximum length to 65,279 bytes split into 256 blocks.
-/// Additionally, 25 bytes are reserved for the message timestamp, bringing the length per message down to 65,254 bytes.
-#[derive(Clone, Debug)]
-pub struct ChatMessage {
-    pub text: String,
-    pub sent: DateTime<Local>,
+/// How a request reached us, which determines how many replies it may get.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Medium {
+    /// A single datagram, answered by a single reply of limited size
+    Datagram,
+    /// A connection, answered by any number of replies
+    Stream,
 }
 
-impl ChatMessage {
-    /// Converts a string into a series of timestamped chat messages.
-    /// Should the length of the message exceed 65279 bytes, it is split into smaller chunks.
-    pub fn from_str(mut msg: String) -> Vec<Self> {
-        let timestamp = Local::now();
-
-        // check if we need to split the message in several parts
-        if msg.as_bytes().len() > MAX_MSG_LENGTH {
-            // compute the # of necessary splits
-            // TODO(feliix42): This could lead to an error when the message contains numerous badly aligned multi-byte characters and is sufficiently long. This would cause the string to be shifted to the right numerous times, outrunning the boudary calculated here. It's however very rare that this will happen.
-            let msg_count = msg.as_bytes().len() / MAX_MSG_LENGTH
-                + (msg.as_bytes().len() % MAX_MSG_LENGTH != 0) as usize;
-            let mut messages = Vec::new();
-
-            for _ in 0..(msg_count - 1) {
-                let remainder = if msg.is_char_boundary(MAX_MSG_LENGTH) {
-                    msg.split_off(MAX_MSG_LENGTH)
-                } else {
-                    msg.split_off(MAX_MSG_LENGTH - 1)
-                };
-
-                messages.push(Self {
-                    text: msg,
-                    sent: timestamp,
-                });
-                msg = remainder;
-            }
+/// Answers the requests of peers received by a `Transport`.
+pub trait Handler: Send + Sync {
+    /// Builds the replies to `request`. Only the first one is sent if the request arrived as a datagram.
+    fn handle(&self, request: &DNSMessage, medium: Medium) -> Vec<DNSMessage>;
+}
+
+/// A way of exchanging DNS messages with peers.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Sends `request` to the peer and returns all replies matching it.
+    async fn query(&self, request: &DNSMessage) -> io::Result<Vec<DNSMessage>>;
 
-            messages.push(Self {
-                text: msg,
-                sent: timestamp,
-            });
-
-            messages
-        } else {
-            vec![Self {
-                text: msg,
-                sent: timestamp,
-            }]
+    /// Answers the requests of peers with `handler` until an error occurs.
+    async fn serve(&self, handler: Arc<dyn Handler>) -> io::Result<()>;
+
+    /// Sends all further queries to `host` and `port`, failing if the host cannot be resolved.
+    async fn set_target(&self, _host: String, _port: u16) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "transport has no target to change",
+        ))
+    }
+}
+
+/// Creates an empty reply to `request`, carrying our own EDNS record if the request had one.
+pub fn reply_template(request: &DNSMessage) -> DNSMessage {
+    let mut reply = request.clone();
+    reply.set_response();
+    if request.edns().is_some() {
+        reply.set_edns(Edns::new(EDNS_PAYLOAD_SIZE));
+    }
+    reply
+}
+
+/// Packs `entries` into as few replies based on `template` as possible, each at most `size_limit` bytes long.
+///
+/// Entries keep their order. An entry exceeding the limit on its own still gets a reply of its own.
+pub fn pack_replies<'a>(
+    template: &DNSMessage,
+    entries: impl IntoIterator<Item = &'a RecordData>,
+    size_limit: usize,
+) -> Vec<DNSMessage> {
+    let template_length = Vec::<u8>::from(template.clone()).len();
+    let mut replies = Vec::new();
+    let mut reply = template.clone();
+    let mut reply_length = template_length;
+
+    for entry in entries {
+        let entry_length = answer_length(template, template_length, entry);
+        if reply.header.answer_count > 0 && reply_length + entry_length > size_limit {
+            replies.push(std::mem::replace(&mut reply, template.clone()));
+            reply_length = template_length;
         }
+        reply.add_answer(entry.clone());
+        reply_length += entry_length;
+    }
+
+    if reply.header.answer_count > 0 || replies.is_empty() {
+        replies.push(reply);
     }
+    replies
+}
+
+/// Packs the leading `entries` into a single reply based on `template`, stopping at the first entry that does not fit into `size_limit` bytes anymore.
+///
+/// Returns `None` if not even the first entry fits, e.g. for a datagram that has to be retried via TCP.
+pub fn pack_reply<'a>(
+    template: &DNSMessage,
+    entries: impl IntoIterator<Item = &'a RecordData>,
+    size_limit: usize,
+) -> Option<DNSMessage> {
+    let template_length = Vec::<u8>::from(template.clone()).len();
+    let mut reply = template.clone();
+    let mut reply_length = template_length;
 
-    /// Converts a DNS message that was received into a vector of `ChatMessage` objects
-    pub fn from_dns(dns_msg: DNSMessage) -> Vec<Self> {
-        if let Some(mut answers) = dns_msg.answers {
-            answers
-                .drain(..)
-                .filter(|m| m.rtype == RecordType::TXT)
-                .map(|m| ChatMessage::from(m.record))
-                .collect()
-        } else {
-            Vec::with_capacity(0)
+    for entry in entries {
+        let entry_length = answer_length(template, template_length, entry);
+        if reply_length + entry_length > size_limit {
+            if reply.header.answer_count == 0 {
+                return None;
+            }
+            break;
         }
+        reply.add_answer(entry.clone());
+        reply_length += entry_length;
     }
+    Some(reply)
 }
 
-impl Into<RecordData> for ChatMessage {
-    fn into(self) -> RecordData {
-        // append the time stamp to the message
-        let mut msg_str = self.text;
-        msg_str.insert_str(0, &self.sent.to_rfc3339_opts(SecondsFormat::Secs, false));
+/// The number of bytes `entry` adds to a reply based on `template` when added as answer.
+fn answer_length(template: &DNSMessage, template_length: usize, entry: &RecordData) -> usize {
+    // answers refer to the question with a compression pointer, so their size does not depend on the other answers
+    let mut single = template.clone();
+    single.add_answer(entry.clone());
+    Vec::<u8>::from(single).len() - template_length
+}
 
-        let mut strings = Vec::new();
+/// Computes how many bytes of TXT data fit into the answer to `reply` without the encoded message exceeding `size_limit` bytes.
+///
+/// `reply` is expected to contain everything but the answer, which will be added for the question at hand.
+fn txt_capacity(reply: &DNSMessage, size_limit: usize) -> usize {
+    let encoded: Vec<u8> = reply.clone().into();
+    // the answer refers to the question name with a compression pointer and adds type, class,
+    // TTL and RDLENGTH
+    let overhead = encoded.len() + 2 + 10;
 
-        while !msg_str.is_empty() {
-            let offset = if msg_str.len() < 255 {
-                msg_str.len()
-            } else if msg_str.is_char_boundary(255) {
-                255
-            } else {
-                254
-            };
+    // every character-string of up to 255 bytes is prefixed with its length
+    let rdata_length = size_limit.saturating_sub(overhead).min(u16::MAX as usize);
+    rdata_length - rdata_length.div_ceil(255)
+}
+
+/// Converts chat messages to and from their representation on the wire, encrypting them if a cipher is set (see `transport::crypto`).
+#[derive(Clone, Default)]
+pub struct Codec {
+    cipher: Option<Cipher>,
+}
+
+impl Codec {
+    pub fn new(cipher: Option<Cipher>) -> Self {
+        Self { cipher }
+    }
+
+    /// Converts a part of a message into its payload (see `transport::envelope`).
+    pub fn encode_payload(&self, part: &Part) -> Vec<u8> {
+        self.seal(part, &[])
+    }
+
+    /// Converts a payload back into a part of a message, rejecting malformed or forged ones.
+    pub fn decode_payload(&self, payload: &[u8]) -> Option<Part> {
+        self.open(payload, &[])
+    }
+
+    /// Converts a part of a message into the character-strings of a TXT record.
+    ///
+    /// Encrypted payloads are base32 encoded to keep them readable for resolvers expecting text. They are bound to `context`, e.g. the sequence number sent along with them (see `Cipher::seal`).
+    pub fn encode_txt(&self, part: &Part, context: &[u8]) -> Vec<Vec<u8>> {
+        let payload = match &self.cipher {
+            Some(_) => base32::encode(&self.seal(part, context)).into_bytes(),
+            None => self.seal(part, context),
+        };
+        payload.chunks(255).map(<[u8]>::to_vec).collect()
+    }
+
+    /// Converts the character-strings of a TXT record encoded in `context` back into a part of a message.
+    pub fn decode_txt(&self, strings: &[Vec<u8>], context: &[u8]) -> Option<Part> {
+        let payload = strings.concat();
+        match &self.cipher {
+            Some(_) => self.open(
+                &base32::decode(std::str::from_utf8(&payload).ok()?)?,
+                context,
+            ),
+            None => self.open(&payload, context),
+        }
+    }
 
-            let remainder = msg_str.split_off(offset);
-            strings.push(msg_str);
-            msg_str = remainder;
+    fn seal(&self, part: &Part, context: &[u8]) -> Vec<u8> {
+        let payload = envelope::encode(part);
+        match &self.cipher {
+            Some(cipher) => cipher.seal(&payload, context),
+            None => payload,
         }
+    }
+
+    fn open(&self, payload: &[u8], context: &[u8]) -> Option<Part> {
+        let payload = match &self.cipher {
+            Some(cipher) => cipher.open(payload, context)?,
+            None => payload.to_vec(),
+        };
+        envelope::decode(&payload)
+    }
+
+    /// Computes how many bytes of message data fit into the answer to `reply` without the encoded message exceeding `size_limit` bytes.
+    ///
+    /// `txt_overhead` is the number of bytes taken by additional character-strings of the answer.
+    pub fn data_capacity(
+        &self,
+        reply: &DNSMessage,
+        size_limit: usize,
+        txt_overhead: usize,
+    ) -> usize {
+        let txt_length = txt_capacity(reply, size_limit).saturating_sub(txt_overhead);
+        let payload_length = match &self.cipher {
+            // every base32 character carries 5 bits
+            Some(_) => (txt_length * 5 / 8).saturating_sub(crypto::OVERHEAD),
+            None => txt_length,
+        };
 
-        RecordData::Txt(strings)
+        payload_length.saturating_sub(envelope::OVERHEAD)
     }
 }
 
-impl From<RecordData> for ChatMessage {
-    fn from(dns_msg: RecordData) -> Self {
-        if let RecordData::Txt(mut strings) = dns_msg {
-            let timestamp = DateTime::from(
-                DateTime::parse_from_rfc3339(&strings[0][0..25])
-                    .expect("Sender delivered malformed date information"),
-            );
-            strings[0] = strings[0].split_off(25);
+/// Marks the data of a message carrying text.
+const TEXT_MESSAGE: u8 = b'T';
+
+/// Marks the data of a message describing an action of its author, like `/me` on IRC.
+const ACTION_MESSAGE: u8 = b'A';
+
+/// Marks the data of a message carrying a file.
+const FILE_MESSAGE: u8 = b'F';
+
+/// Length of the SHA-256 digest of a file.
+const DIGEST_LENGTH: usize = 32;
+
+/// Representation of a single timestamped message
+///
+/// Messages may be of any length. Those exceeding the space available in a single reply or relay entry are split into parts on the wire (see `transport::parts`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatMessage {
+    /// Identifies the message, which is sent along as id of its parts
+    pub id: u32,
+    /// The name the author chose to be shown with
+    pub nick: Option<String>,
+    pub text: String,
+    pub sent: DateTime<Local>,
+    /// Whether the text describes an action of the author
+    pub action: bool,
+    /// A file sent instead of text
+    pub attachment: Option<Attachment>,
+}
+
+/// A file carried by a message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attachment {
+    /// The name of the file, without any directories
+    pub name: String,
+    pub data: Vec<u8>,
+}
 
-            let mut msg = String::with_capacity(strings.iter().fold(0, |acc, s| acc + s.len()));
-            for s in &strings {
-                msg.push_str(s);
+impl ChatMessage {
+    /// Creates a message sent right now.
+    pub fn new(text: String) -> Self {
+        Self {
+            id: rand::random(),
+            nick: None,
+            text,
+            sent: Local::now(),
+            action: false,
+            attachment: None,
+        }
+    }
+
+    /// Encodes everything but the timestamp, which is sent along with every part.
+    ///
+    /// The kind of message is followed by the nickname of the author, which is empty if there is none.
+    /// A file is preceded by its SHA-256 digest, which is verified on arrival, and its name. Names are truncated to 255 bytes. The text of a message carrying a file is not sent.
+    pub fn encode_data(&self) -> Vec<u8> {
+        let kind = match (&self.attachment, self.action) {
+            (Some(_), _) => FILE_MESSAGE,
+            (None, true) => ACTION_MESSAGE,
+            (None, false) => TEXT_MESSAGE,
+        };
+        let mut data = vec![kind];
+        push_short_string(&mut data, self.nick.as_deref().unwrap_or(""));
+
+        match &self.attachment {
+            None => data.extend_from_slice(self.text.as_bytes()),
+            Some(attachment) => {
+                data.extend_from_slice(&Sha256::digest(&attachment.data));
+                push_short_string(&mut data, &attachment.name);
+                data.extend_from_slice(&attachment.data);
             }
+        }
+        data
+    }
+
+    /// Decodes a message encoded with `encode_data`, rejecting files that do not match their digest.
+    pub fn decode_data(id: u32, data: &[u8], sent: DateTime<Local>) -> Option<Self> {
+        let (kind, data) = data.split_first()?;
+        let (nick, data) = read_short_string(data)?;
+        let mut msg = Self::new(String::new());
+        msg.id = id;
+        msg.sent = sent;
+        msg.nick = Some(nick.to_string()).filter(|nick| !nick.is_empty());
 
-            Self {
-                text: msg,
-                sent: timestamp,
+        match *kind {
+            TEXT_MESSAGE | ACTION_MESSAGE => {
+                msg.text = String::from_utf8(data.to_vec()).ok()?;
+                msg.action = *kind == ACTION_MESSAGE;
             }
-        } else {
-            panic!("Non-text RecordData type")
+            FILE_MESSAGE => {
+                let digest = data.get(..DIGEST_LENGTH)?;
+                let (name, data) = read_short_string(&data[DIGEST_LENGTH..])?;
+                if Sha256::digest(data).as_slice() != digest {
+                    eprintln!("[transport] Dropping file {} not matching its digest", name);
+                    return None;
+                }
+                msg.attachment = Some(Attachment {
+                    name: name.to_string(),
+                    data: data.to_vec(),
+                });
+            }
+            _ => return None,
         }
+        Some(msg)
+    }
+}
+
+/// Appends `string` preceded by its length, truncating it to 255 bytes without splitting a character.
+fn push_short_string(data: &mut Vec<u8>, string: &str) {
+    let mut length = string.len().min(u8::MAX as usize);
+    while !string.is_char_boundary(length) {
+        length -= 1;
     }
+    data.push(length as u8);
+    data.extend_from_slice(&string.as_bytes()[..length]);
+}
+
+/// Reads a string written by `push_short_string`, returning it along with the remaining data.
+fn read_short_string(data: &[u8]) -> Option<(&str, &[u8])> {
+    let (length, data) = data.split_first()?;
+    let length = *length as usize;
+    let string = std::str::from_utf8(data.get(..length)?).ok()?;
+    Some((string, &data[length..]))
+}
+
+/// Progress of a message split into several parts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Progress {
+    /// Identifies the message (see `Part::id`)
+    pub id: u32,
+    /// Whether the message is sent rather than received
+    pub outgoing: bool,
+    /// Number of parts transferred so far
+    pub done: u16,
+    /// Number of parts making up the message
+    pub total: u16,
+}
+
+/// What happened on the connection to the peer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Activity {
+    /// The peer answered a query after `latency`
+    Answered { latency: Duration },
+    /// The peer sent a query, which was answered
+    Queried,
+    /// The peer could not be reached
+    Unreachable(String),
+    /// Bytes of DNS messages sent to and received from the peer
+    Traffic { sent: usize, received: usize },
+    /// Number of messages or parts of them waiting to be sent or acknowledged
+    Queue(usize),
+}
+
+/// What the transport reports to the user interface.
+#[derive(Clone, Debug)]
+pub enum Update {
+    /// A message arrived
+    Message(ChatMessage),
+    /// A part of a message was transferred
+    Progress(Progress),
+    /// The peer received all of the message with the given id
+    Delivered(u32),
+    /// The message with the given id could not be delivered so far, but will be retried
+    Undelivered(u32),
+    /// Something happened on the connection
+    Activity(Activity),
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::convert::TryFrom;
+
+    fn whole(msg: ChatMessage) -> Part {
+        parts::split(&msg, usize::MAX).remove(0)
+    }
 
     #[test]
-    fn conversion_to_record() {
-        let date = DateTime::from(DateTime::parse_from_rfc3339("2020-12-24T18:34:16+01:00").unwrap());
-        let expected_date_string = date.to_rfc3339_opts(SecondsFormat::Secs, false);
+    fn conversion_to_txt() {
+        let date =
+            DateTime::from(DateTime::parse_from_rfc3339("2020-12-24T18:34:16.250+01:00").unwrap());
+        let mut msg = ChatMessage::new("a".repeat(250));
+        msg.sent = date;
+        let part = whole(msg);
+
+        let strings = Codec::default().encode_txt(&part, &[]);
+        assert_eq!(strings.len(), 2);
+        assert_eq!(strings[0].len(), 255);
+        assert_eq!(strings.concat(), envelope::encode(&part));
+        let decoded = Codec::default().decode_txt(&strings, &[]).unwrap();
+        assert_eq!(decoded, part);
+    }
 
-        let msg = ChatMessage {
-            text: String::from("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"),
-            sent: date
+    #[test]
+    fn binary_data_round_trips() {
+        // character-strings may hold any bytes, including ones that are not valid UTF-8
+        let mut msg = ChatMessage::new(String::new());
+        msg.attachment = Some(Attachment {
+            name: "blob.bin".into(),
+            data: (0..=255).cycle().take(1000).collect(),
+        });
+
+        let strings = Codec::default().encode_txt(&whole(msg.clone()), &[]);
+        assert!(strings.iter().all(|s| s.len() <= 255));
+        let mut reply = DNSMessage::new_request(1, "ifsr.de".into());
+        reply.add_answer(RecordData::Txt(strings));
+        let parsed = DNSMessage::try_from(Vec::<u8>::from(reply).as_slice()).unwrap();
+
+        let part = match parsed.answers.unwrap().remove(0).record {
+            RecordData::Txt(strings) => Codec::default().decode_txt(&strings, &[]).unwrap(),
+            _ => panic!("not a TXT record"),
         };
+        let received = ChatMessage::decode_data(part.id, &part.data, part.sent).unwrap();
+        assert_eq!(received.attachment, msg.attachment);
+    }
+
+    #[test]
+    fn nicknames_round_trip() {
+        let mut msg = ChatMessage::new("waves".into());
+        msg.action = true;
+        msg.nick = Some("ü".repeat(200));
+        let data = msg.encode_data();
+        let received = ChatMessage::decode_data(msg.id, &data, msg.sent).unwrap();
+        // the nickname is cut off at a character boundary
+        assert_eq!(received.nick, Some("ü".repeat(127)));
+        assert_eq!((received.text.as_str(), received.action), ("waves", true));
+
+        msg.nick = None;
+        let data = msg.encode_data();
+        assert_eq!(ChatMessage::decode_data(msg.id, &data, msg.sent), Some(msg));
+    }
+
+    #[test]
+    fn replies_fit_into_payload_size() {
+        let mut reply = DNSMessage::new_request(23481, "ifsr.de".into());
+        reply.set_edns(Edns::new(EDNS_PAYLOAD_SIZE));
+
+        let secret = crypto::generate_key();
+        let cipher = Cipher::new(&secret, &crypto::PublicKey::from(&secret)).unwrap();
+
+        for codec in [Codec::default(), Codec::new(Some(cipher))].iter() {
+            let capacity = codec.data_capacity(&reply, 1232, 0);
+            let msg = ChatMessage::new("a".repeat(5000));
+
+            for part in parts::split(&msg, capacity) {
+                let mut answered = reply.clone();
+                answered.add_answer(RecordData::Txt(codec.encode_txt(&part, &[])));
+                let encoded: Vec<u8> = answered.into();
+                assert!(encoded.len() <= 1232);
+            }
+        }
+    }
+
+    #[test]
+    fn entries_are_packed_into_replies() {
+        let request = DNSMessage::new_request(23481, "ifsr.de".into());
+        let template = reply_template(&request);
+        let entries: Vec<RecordData> = (0..10)
+            .map(|i| RecordData::Txt(vec![i.to_string().into_bytes(), vec![b'a'; 200]]))
+            .collect();
+
+        let replies = pack_replies(&template, &entries, 512);
+        assert_eq!(replies.len(), 5);
+        let packed: Vec<RecordData> = replies
+            .into_iter()
+            .flat_map(|reply| {
+                assert!(Vec::<u8>::from(reply.clone()).len() <= 512);
+                assert!(reply.is_reply_to(&request));
+                let answers = reply.answers.unwrap();
+                assert_eq!(reply.header.answer_count as usize, answers.len());
+                answers.into_iter().map(|answer| answer.record)
+            })
+            .collect();
+        assert_eq!(packed, entries);
+
+        assert_eq!(pack_replies(&template, &[], 512), vec![template.clone()]);
+
+        // a single reply stops at the first entry not fitting anymore
+        let reply = pack_reply(&template, &entries, 512).unwrap();
+        assert!(Vec::<u8>::from(reply.clone()).len() <= 512);
+        assert_eq!(reply.header.answer_count, 2);
+        assert_eq!(pack_reply(&template, &entries, 100), None);
+        assert_eq!(pack_reply(&template, &[], 100), Some(template));
+    }
+
+    #[test]
+    fn encrypted_messages_round_trip() {
+        let alice = crypto::generate_key();
+        let bob = crypto::generate_key();
+        let to_bob = Codec::new(Cipher::new(&alice, &crypto::PublicKey::from(&bob)));
+        let from_alice = Codec::new(Cipher::new(&bob, &crypto::PublicKey::from(&alice)));
+
+        let part = whole(ChatMessage::new("ünïcödé ".repeat(100)));
+        let strings = to_bob.encode_txt(&part, &[]);
+        assert!(strings.iter().all(|s| s.len() <= 255));
+        // encrypted payloads are base32 encoded, as they would not be readable otherwise
+        let concatenated = String::from_utf8(strings.concat()).unwrap();
+        assert!(!concatenated.contains("ünïcödé"));
 
-        let expected = RecordData::Txt(vec![
-            format!("{date}aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", date=expected_date_string),
-            String::from("aaaaaaaaaaaaaaaaaaaaaaaaa")
-        ]);
+        let received = from_alice.decode_txt(&strings, &[]).unwrap();
+        assert_eq!(received.data, part.data);
+        assert_eq!(received.sent.timestamp(), part.sent.timestamp());
 
-        let res: RecordData = msg.into();
-        assert_eq!(res, expected);
+        // without the key, the message cannot be read
+        assert!(Codec::default().decode_txt(&strings, &[]).is_none());
     }
 }
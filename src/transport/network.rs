@@ -0,0 +1,309 @@
+//! Transports exchanging DNS messages over the network.
+//!
+//! Like DNS itself, `Udp` sends queries as datagrams and retries them via TCP if the reply was truncated, so a peer serving via UDP listens for TCP connections on the same port as well.
+
+use super::framing::{self, FrameReader};
+use super::{Handler, Medium, Transport};
+use crate::dns::messages::DNSMessage;
+use async_trait::async_trait;
+use std::convert::TryFrom;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{self, TcpListener, TcpStream, UdpSocket};
+use tokio::time::{self, Instant};
+
+/// How long to wait for the reply to a query sent via UDP, and for a TCP connection to be established.
+const UDP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Time to wait before accepting connections again after an error.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Host and port of the peer to query, which may change while queries are sent.
+type Target = Mutex<Option<(String, u16)>>;
+
+/// Exchanges messages via TCP only.
+pub struct Tcp {
+    /// Host and port of the peer to query
+    target: Target,
+    /// Port to serve requests on
+    listening_port: u16,
+}
+
+impl Tcp {
+    pub fn new(listening_port: u16) -> Self {
+        Self {
+            target: Mutex::new(None),
+            listening_port,
+        }
+    }
+
+    /// Sets the peer to send queries to.
+    pub fn with_target(self, host: String, port: u16) -> Self {
+        *self.target.lock().unwrap() = Some((host, port));
+        self
+    }
+}
+
+#[async_trait]
+impl Transport for Tcp {
+    async fn query(&self, request: &DNSMessage) -> io::Result<Vec<DNSMessage>> {
+        let (host, port) = target(&self.target)?;
+        query_tcp((&host, port), request).await
+    }
+
+    async fn serve(&self, handler: Arc<dyn Handler>) -> io::Result<()> {
+        let listener = TcpListener::bind(("0.0.0.0", self.listening_port)).await?;
+        loop {
+            let stream = accept(&listener).await;
+            tokio::spawn(answer_tcp(stream, handler.clone()));
+        }
+    }
+
+    async fn set_target(&self, host: String, port: u16) -> io::Result<()> {
+        set_target(&self.target, host, port).await
+    }
+}
+
+/// Exchanges messages via UDP, falling back to TCP for replies that do not fit into a datagram.
+pub struct Udp {
+    /// Host and port of the peer to query
+    target: Target,
+    /// Port to serve requests on
+    listening_port: u16,
+}
+
+impl Udp {
+    pub fn new(listening_port: u16) -> Self {
+        Self {
+            target: Mutex::new(None),
+            listening_port,
+        }
+    }
+
+    /// Sets the peer to send queries to.
+    pub fn with_target(self, host: String, port: u16) -> Self {
+        *self.target.lock().unwrap() = Some((host, port));
+        self
+    }
+}
+
+#[async_trait]
+impl Transport for Udp {
+    async fn query(&self, request: &DNSMessage) -> io::Result<Vec<DNSMessage>> {
+        let (host, port) = target(&self.target)?;
+        let target = (host.as_str(), port);
+        let reply = query_udp(target, request).await?;
+        // like a stub resolver, retry truncated replies via TCP
+        if reply.header.is_truncated {
+            query_tcp(target, request).await
+        } else {
+            Ok(vec![reply])
+        }
+    }
+
+    async fn serve(&self, handler: Arc<dyn Handler>) -> io::Result<()> {
+        let socket = UdpSocket::bind(("0.0.0.0", self.listening_port)).await?;
+        let listener = TcpListener::bind(("0.0.0.0", self.listening_port)).await?;
+        let mut buf = [0; 65535];
+
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut buf) => {
+                    let (read_length, remote_addr) = match received {
+                        Ok(received) => received,
+                        Err(e) => {
+                            eprintln!("[transport] Could not receive request: {}", e);
+                            continue;
+                        }
+                    };
+                    let request = match DNSMessage::try_from(&buf[..read_length]) {
+                        Ok(request) => request,
+                        Err(e) => {
+                            eprintln!("[transport] Dropping malformed request: {}", e);
+                            continue;
+                        }
+                    };
+
+                    let reply = handler.handle(&request, Medium::Datagram).into_iter().next();
+                    if let Some(reply) = reply {
+                        if let Err(e) = socket.send_to(&Vec::<u8>::from(reply), remote_addr).await {
+                            eprintln!("[transport] Could not send reply: {}", e);
+                        }
+                    }
+                }
+
+                // answer connections concurrently, so a slow peer does not hold up others
+                stream = accept(&listener) => {
+                    tokio::spawn(answer_tcp(stream, handler.clone()));
+                }
+            }
+        }
+    }
+
+    async fn set_target(&self, host: String, port: u16) -> io::Result<()> {
+        set_target(&self.target, host, port).await
+    }
+}
+
+/// Changes the peer to query, failing if `host` cannot be resolved.
+async fn set_target(target: &Target, host: String, port: u16) -> io::Result<()> {
+    // queries resolve the host again, as its address may change
+    if net::lookup_host((host.as_str(), port))
+        .await?
+        .next()
+        .is_none()
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no address", host),
+        ));
+    }
+    *target.lock().unwrap() = Some((host, port));
+    Ok(())
+}
+
+/// The peer to query, failing if none was set.
+fn target(target: &Target) -> io::Result<(String, u16)> {
+    match &*target.lock().unwrap() {
+        Some((host, port)) => Ok((host.clone(), *port)),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "no peer to send queries to",
+        )),
+    }
+}
+
+/// Accepts the next connection.
+///
+/// Errors like running out of file descriptors are temporary, so they are logged and accepting is retried after a pause instead of giving up serving.
+async fn accept(listener: &TcpListener) -> TcpStream {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _remote_addr)) => return stream,
+            Err(e) => {
+                eprintln!("[transport] Could not accept connection: {}", e);
+                time::sleep(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+/// Reads a request from `stream` and writes all replies `handler` builds for it.
+async fn answer_tcp(mut stream: TcpStream, handler: Arc<dyn Handler>) {
+    let frame = match FrameReader::new(&mut stream).read_frame().await {
+        Ok(Some(frame)) => frame,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("[transport] Could not read request: {}", e);
+            return;
+        }
+    };
+    let request = match DNSMessage::try_from(frame.as_slice()) {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("[transport] Dropping malformed request: {}", e);
+            return;
+        }
+    };
+
+    for reply in handler.handle(&request, Medium::Stream) {
+        if let Err(e) = framing::write_frame(&mut stream, &Vec::<u8>::from(reply)).await {
+            eprintln!("[transport] Could not send reply: {}", e);
+            break;
+        }
+    }
+}
+
+/// Sends `request` via TCP and collects all replies until the peer closes the connection.
+async fn query_tcp(target: (&str, u16), request: &DNSMessage) -> io::Result<Vec<DNSMessage>> {
+    let mut received = Vec::new();
+
+    // an unreachable peer would otherwise hold up polling until the system gives up
+    let mut stream = time::timeout(UDP_TIMEOUT, TcpStream::connect(target))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    framing::write_frame(&mut stream, &Vec::<u8>::from(request.clone())).await?;
+
+    // receive messages until everything has been transmitted
+    let mut frames = FrameReader::new(&mut stream);
+    loop {
+        let frame = match frames.read_frame().await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => break,
+            Err(e) => return Err(e),
+        };
+
+        match DNSMessage::try_from(frame.as_slice()) {
+            Ok(parsed) if parsed.is_reply_to(request) => received.push(parsed),
+            Ok(_) => {
+                eprintln!("[transport] Discarding reply not matching the query, possibly spoofed")
+            }
+            Err(e) => eprintln!("[transport] Dropping malformed reply: {}", e),
+        }
+    }
+
+    Ok(received)
+}
+
+/// Sends `request` as a single datagram and waits for the reply.
+///
+/// Every query is sent from a new socket bound to a random port, which makes spoofing replies harder.
+/// Like a stub resolver, datagrams not matching the query or malformed are discarded while waiting, since they may have been spoofed.
+async fn query_udp(target: (&str, u16), request: &DNSMessage) -> io::Result<DNSMessage> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    let mut buf = [0; 65535];
+    socket
+        .send_to(&Vec::<u8>::from(request.clone()), target)
+        .await?;
+
+    // discarded datagrams must not extend the time spent waiting
+    let deadline = Instant::now() + UDP_TIMEOUT;
+    loop {
+        let read_length = time::timeout_at(deadline, socket.recv(&mut buf))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        match DNSMessage::try_from(&buf[..read_length]) {
+            Ok(parsed) if parsed.is_reply_to(request) => return Ok(parsed),
+            Ok(_) => {
+                eprintln!("[transport] Discarding reply not matching the query, possibly spoofed")
+            }
+            Err(e) => eprintln!("[transport] Dropping malformed reply: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn connecting_times_out() {
+        // a reserved address nobody answers on
+        let request = DNSMessage::new_request(1, "chat.example".into());
+        let started = Instant::now();
+        if let Err(e) = query_tcp(("192.0.2.1", 53), &request).await {
+            assert!(
+                started.elapsed() < UDP_TIMEOUT + Duration::from_secs(1),
+                "{}",
+                e
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn targets_are_resolved() {
+        let transport = Udp::new(0);
+        transport.set_target("127.0.0.1".into(), 53).await.unwrap();
+        assert!(transport
+            .set_target("nosuchhost.invalid".into(), 53)
+            .await
+            .is_err());
+        // the previous target is kept
+        assert_eq!(
+            target(&transport.target).unwrap(),
+            ("127.0.0.1".to_string(), 53)
+        );
+    }
+}
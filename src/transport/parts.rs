@@ -0,0 +1,223 @@
+//! Splitting messages into parts and putting them back together.
+//!
+//! A message too long for a single reply or relay entry is split into parts, which are sent like messages of their own.
+//! Every part carries the id of its message, its index and the total number of parts in its payload, so they are encrypted and authenticated along with the data (see `transport::envelope`).
+
+use super::{ChatMessage, Progress, Update};
+use chrono::{DateTime, Local};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Maximum number of bytes of data in a part carried upstream, small enough for a relay to return it encrypted and base32 encoded within a single reply of `EDNS_PAYLOAD_SIZE`.
+pub const MAX_UPSTREAM_LENGTH: usize = 2048;
+
+/// Incomplete messages are discarded after this time.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Maximum number of incomplete messages kept, the oldest one being discarded for a new one.
+const MAX_PENDING_MESSAGES: usize = 16;
+
+/// Maximum number of bytes of data kept for an incomplete message, well above the largest attachment.
+const MAX_MESSAGE_LENGTH: usize = 16 * 1024 * 1024;
+
+/// A piece of a message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Part {
+    /// Identifies the message the part belongs to
+    pub id: u32,
+    /// Position of the part within the message
+    pub index: u16,
+    /// Number of parts making up the message
+    pub count: u16,
+    /// Timestamp of the message
+    pub sent: DateTime<Local>,
+    /// The piece of the encoded message (see `ChatMessage::encode_data`)
+    pub data: Vec<u8>,
+}
+
+/// Splits `msg` into parts carrying at most `max_len` bytes of data each, identified by the id of the message.
+///
+/// Very long messages get longer parts, as the number of parts is limited to what the header can express.
+pub fn split(msg: &ChatMessage, max_len: usize) -> Vec<Part> {
+    let data = msg.encode_data();
+    let max_len = max_len.max(data.len().div_ceil(u16::MAX as usize)).max(1);
+    let id = msg.id;
+    let count = data.len().div_ceil(max_len) as u16;
+
+    data.chunks(max_len)
+        .enumerate()
+        .map(|(index, chunk)| Part {
+            id,
+            index: index as u16,
+            count,
+            sent: msg.sent,
+            data: chunk.to_vec(),
+        })
+        .collect()
+}
+
+/// The parts of a message received so far.
+struct Pieces {
+    /// Time the first part arrived
+    started: Instant,
+    /// Number of parts making up the message
+    count: u16,
+    /// Data of the parts by index
+    data: HashMap<u16, Vec<u8>>,
+    /// Number of bytes of data received
+    length: usize,
+}
+
+/// Collects parts until the messages they belong to are complete.
+#[derive(Default)]
+pub struct Assembler {
+    /// Parts of incomplete messages by message id
+    pending: HashMap<u32, Pieces>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a part, returning the message once all of its parts have arrived and the progress until then.
+    ///
+    /// Returns `None` for parts that do not belong to a valid message.
+    pub fn add(&mut self, part: Part) -> Option<Update> {
+        if part.count == 1 {
+            return ChatMessage::decode_data(part.id, &part.data, part.sent).map(Update::Message);
+        }
+
+        self.pending
+            .retain(|_, pieces| pieces.started.elapsed() < REASSEMBLY_TIMEOUT);
+        if !self.pending.contains_key(&part.id) && self.pending.len() >= MAX_PENDING_MESSAGES {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, pieces)| pieces.started)
+                .map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                eprintln!(
+                    "[transport] Too many incomplete messages, discarding {:08x}",
+                    oldest
+                );
+                self.pending.remove(&oldest);
+            }
+        }
+
+        let pieces = self.pending.entry(part.id).or_insert_with(|| Pieces {
+            started: Instant::now(),
+            count: part.count,
+            data: HashMap::new(),
+            length: 0,
+        });
+        if pieces.count != part.count {
+            // not matching the parts seen so far, so this is garbage
+            return None;
+        }
+        if !pieces.data.contains_key(&part.index) {
+            pieces.length += part.data.len();
+            if pieces.length > MAX_MESSAGE_LENGTH {
+                eprintln!("[transport] Discarding oversized message {:08x}", part.id);
+                self.pending.remove(&part.id);
+                return None;
+            }
+            pieces.data.insert(part.index, part.data);
+        }
+
+        let done = pieces.data.len() as u16;
+        if done < part.count {
+            return Some(Update::Progress(Progress {
+                id: part.id,
+                outgoing: false,
+                done,
+                total: part.count,
+            }));
+        }
+
+        let mut pieces = self.pending.remove(&part.id)?;
+        let data: Vec<u8> = (0..part.count)
+            .flat_map(|index| pieces.data.remove(&index).unwrap_or_default())
+            .collect();
+        ChatMessage::decode_data(part.id, &data, part.sent).map(Update::Message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::Attachment;
+
+    #[test]
+    fn messages_are_reassembled() {
+        let mut msg = ChatMessage::new(String::new());
+        msg.attachment = Some(Attachment {
+            name: "data.bin".into(),
+            data: (0..=255).collect(),
+        });
+        let mut parts = split(&msg, 100);
+        assert_eq!(parts.len(), 3);
+        assert!(parts.iter().all(|part| part.data.len() <= 100));
+
+        // parts of other messages may arrive in between
+        let mut assembler = Assembler::new();
+        let other = split(&ChatMessage::new("a".repeat(200)), 100).remove(0);
+        assert!(matches!(assembler.add(other), Some(Update::Progress(_))));
+
+        let last = parts.pop().unwrap();
+        for (i, part) in parts.into_iter().enumerate() {
+            match assembler.add(part) {
+                Some(Update::Progress(progress)) => assert_eq!(progress.done, i as u16 + 1),
+                _ => panic!("message completed early"),
+            }
+        }
+        match assembler.add(last) {
+            Some(Update::Message(reassembled)) => {
+                assert_eq!(reassembled.id, msg.id);
+                assert_eq!(reassembled.attachment, msg.attachment);
+                assert_eq!(reassembled.sent, msg.sent);
+            }
+            _ => panic!("message not completed"),
+        }
+    }
+
+    #[test]
+    fn pending_messages_are_limited() {
+        let mut assembler = Assembler::new();
+        for id in 0..MAX_PENDING_MESSAGES as u32 * 2 {
+            let part = Part {
+                id,
+                index: 0,
+                count: u16::MAX,
+                sent: Local::now(),
+                data: vec![0; 10],
+            };
+            assert!(matches!(assembler.add(part), Some(Update::Progress(_))));
+        }
+        assert_eq!(assembler.pending.len(), MAX_PENDING_MESSAGES);
+
+        let part = |index| Part {
+            id: 0,
+            index,
+            count: 2,
+            sent: Local::now(),
+            data: vec![0; MAX_MESSAGE_LENGTH / 2 + 1],
+        };
+        assert!(assembler.add(part(0)).is_some());
+        assert!(assembler.add(part(1)).is_none());
+        assert!(!assembler.pending.contains_key(&0));
+    }
+
+    #[test]
+    fn corrupted_files_are_rejected() {
+        let mut msg = ChatMessage::new(String::new());
+        msg.attachment = Some(Attachment {
+            name: "data.bin".into(),
+            data: vec![1, 2, 3],
+        });
+        let mut part = split(&msg, 1000).remove(0);
+        *part.data.last_mut().unwrap() ^= 1;
+
+        assert!(Assembler::new().add(part).is_none());
+    }
+}
@@ -1,89 +1,311 @@
+use crate::dns::edns::Edns;
 use crate::dns::messages::DNSMessage;
-use crate::transport::ChatMessage;
+use crate::relay;
+use crate::transport::parts::{self, Assembler};
+use crate::transport::reliable::Inbox;
+use crate::transport::{
+    upstream, Activity, ChatMessage, Codec, Progress, Transport, Update, EDNS_PAYLOAD_SIZE,
+};
 use std::collections::VecDeque;
-use std::convert::TryFrom;
-use std::io::{self, Read, Write};
-use std::net::{Shutdown, TcpStream, ToSocketAddrs};
-use std::sync::mpsc::{SendError, Sender};
-use std::thread;
-use std::time::Duration;
-
-pub fn poll_messages<A: ToSocketAddrs + Clone>(
-    message_sender: Sender<ChatMessage>,
-    target: A,
-) -> Result<(), SendError<ChatMessage>> {
-    // poll every x seconds for new messages (10s?)
-    // Read until connection is reset (catch that error!)
-    // sleep
-    let mut received: VecDeque<DNSMessage> = VecDeque::new();
-    let mut buf = [0; 65535];
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::time;
+
+/// Time between two polls of the peer.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Number of own messages remembered to recognize them when a relay returns them.
+const OWN_HISTORY: usize = 64;
+
+/// Polls the peer reachable via `transport` for new messages and passes them on to `message_sender`, along with the progress of messages split into several parts and the outcome of every query.
+///
+/// Messages are delivered in order and acknowledged with the next poll (see `transport::reliable`).
+///
+/// If `outgoing` is given, messages received from it are carried upstream in query names instead of waiting for the peer to poll them (see `transport::upstream`).
+///
+/// If `room` is given, the peer is expected to be a relay (see `relay`). Messages are then posted to and read from that room, keeping track of the messages already seen.
+pub async fn poll_messages(
+    message_sender: UnboundedSender<Update>,
+    transport: Arc<dyn Transport>,
+    zone: String,
+    room: Option<String>,
+    mut outgoing: Option<UnboundedReceiver<ChatMessage>>,
+    codec: Codec,
+) -> Result<(), SendError<Update>> {
+    // queries carrying upstream data that still have to be sent
+    let mut pending: VecDeque<Upload> = VecDeque::new();
+    // tells our messages apart from the ones of other peers or earlier runs (see `transport::upstream`)
+    let upstream_session: u32 = rand::random();
+    let mut upstream_id: u16 = 0;
+    // messages received from the peer
+    let mut inbox = Inbox::new(codec.clone());
+    // parts of messages received from the peer or the room
+    let mut assembler = Assembler::new();
+    // number of the first room message not seen yet
+    let mut cursor: u32 = 0;
+    // ids of own messages posted to the room, which are not shown again
+    let mut own: VecDeque<u32> = VecDeque::new();
+
+    let upstream_zone = match &room {
+        Some(room) => format!("{}.{}", room, zone),
+        None => zone.clone(),
+    };
+
+    let mut enqueue =
+        |pending: &mut VecDeque<Upload>, own: &mut VecDeque<u32>, msg: ChatMessage| {
+            let parts = parts::split(&msg, parts::MAX_UPSTREAM_LENGTH);
+            if room.is_some() {
+                own.push_back(parts[0].id);
+                if own.len() > OWN_HISTORY {
+                    own.pop_front();
+                }
+            }
+            for part in parts {
+                let names = upstream::encode_message(
+                    &part,
+                    upstream_session,
+                    upstream_id,
+                    &upstream_zone,
+                    &codec,
+                );
+                let last = names.len() - 1;
+                for (i, name) in names.into_iter().enumerate() {
+                    let mut answered = Vec::new();
+                    if i == last && part.count > 1 {
+                        answered.push(Update::Progress(Progress {
+                            id: part.id,
+                            outgoing: true,
+                            done: part.index + 1,
+                            total: part.count,
+                        }));
+                    }
+                    if i == last && part.index + 1 == part.count {
+                        answered.push(Update::Delivered(part.id));
+                    }
+                    pending.push_back(Upload {
+                        query: DNSMessage::new_request(rand::random(), name)
+                            .with_edns(Edns::new(EDNS_PAYLOAD_SIZE)),
+                        id: part.id,
+                        answered,
+                    });
+                }
+                upstream_id = upstream_id.wrapping_add(1);
+            }
+        };
 
     loop {
-        let message = DNSMessage::new_request(23481, "ifsr.de".into());
-        let mut msg: Vec<u8> = message.into();
-
-        // prepend the length of the message for TCP transfer
-        let len = u16::try_from(msg.len()).unwrap();
-        let split = len.to_be_bytes();
-        msg.insert(0, split[1]);
-        msg.insert(0, split[0]);
-
-        let mut stream = match TcpStream::connect(target.clone()) {
-            Ok(con) => con,
-            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
-                // Couldn't connect to target DNS server
-                thread::sleep(Duration::from_secs(5));
-                continue;
+        if let Some(outgoing) = &mut outgoing {
+            while let Ok(msg) = outgoing.try_recv() {
+                enqueue(&mut pending, &mut own, msg);
+            }
+        }
+
+        // every query doubles as a poll, so send plain polls only if there is no data to carry
+        let (message, upload) = match pending.pop_front() {
+            Some(upload) => (upload.query.clone(), Some(upload)),
+            None => {
+                let name = match &room {
+                    Some(_) => format!("{}.{}", cursor, upstream_zone),
+                    None => inbox.poll_name(&zone),
+                };
+                let message = DNSMessage::new_request(rand::random(), name)
+                    .with_edns(Edns::new(EDNS_PAYLOAD_SIZE));
+                (message, None)
             }
-            Err(e) => panic!("{}", e),
         };
 
-        // TODO(feliix42): error handling
-        stream.write(&msg).unwrap();
-        stream.flush().unwrap();
-
-        // receive messages until everything has been transmitted
-        'inner: loop {
-            // TODO(feliix42): does this read beyond the borders of individual packets?
-            let read_length = match stream.read(&mut buf) {
-                Ok(0) => {
-                    //eprintln!("[receiver] received empty message");
-                    break 'inner;
+        let started = Instant::now();
+        let result = match transport.query(&message).await {
+            // only a reply tells that the query arrived, which may be lost or malformed otherwise
+            Ok(replies) if !replies.is_empty() => Ok(replies),
+            Ok(_) => Err("no reply".to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        let replies = match result {
+            Ok(replies) => replies,
+            Err(e) => {
+                // whatever went wrong, e.g. an unreachable peer or a failing resolver, retry the upstream data later
+                if let Some(upload) = upload {
+                    message_sender.send(Update::Undelivered(upload.id))?;
+                    pending.push_front(upload);
                 }
-                Ok(sz) => sz,
-                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => break 'inner,
-                Err(ref e) => panic!("{}", e),
-            };
+                message_sender.send(Update::Activity(Activity::Unreachable(e)))?;
+                time::sleep(POLL_INTERVAL).await;
+                continue;
+            }
+        };
+        for update in upload.into_iter().flat_map(|upload| upload.answered) {
+            message_sender.send(update)?;
+        }
 
-            let packet_len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+        let traffic = Activity::Traffic {
+            sent: Vec::<u8>::from(message).len(),
+            received: replies
+                .iter()
+                .map(|reply| Vec::<u8>::from(reply.clone()).len())
+                .sum(),
+        };
+        let mut activities = vec![
+            Activity::Answered {
+                latency: started.elapsed(),
+            },
+            traffic,
+        ];
+        if outgoing.is_some() {
+            activities.push(Activity::Queue(pending.len()));
+        }
+        for activity in activities {
+            message_sender.send(Update::Activity(activity))?;
+        }
 
-            // NOTE(feliix42): RFC 1035, 4.2.2 - TCP usage requires prepending the message with 2
-            // bytes length information that does not include said two bytes
-            let parsed = DNSMessage::from(&buf[2..packet_len + 2]);
+        // a reply to a datagram carries a single entry of a room, so keep asking while there are more
+        let mut drain_queue =
+            room.is_some() && replies.iter().any(|reply| reply.header.answer_count > 0);
 
-            // clear the buffer
-            for i in 0..read_length {
-                buf[i] = 0;
+        // if messages were received, convert them and pass them on to the user interface
+        for msg in replies {
+            let updates: Vec<Update> = match room {
+                Some(_) => room_messages(msg, &mut cursor, &own, &codec, &mut assembler),
+                None => msg
+                    .answers
+                    .unwrap_or_default()
+                    .iter()
+                    .flat_map(|answer| inbox.receive(&answer.record))
+                    .filter_map(|part| assembler.add(part))
+                    .collect(),
+            };
+            // acknowledge new messages right away, which also asks for the next ones
+            drain_queue |= room.is_none() && !updates.is_empty();
+            for update in updates {
+                message_sender.send(update)?;
             }
-            received.push_back(parsed);
         }
 
-        if !received.is_empty() {
-            // is messages were received, convert them and send them back to the main thread
-            for msg in received.drain(..) {
-                let chat_messages = ChatMessage::from_dns(msg);
-                for chat_msg in chat_messages {
-                    message_sender.send(chat_msg)?;
-                }
-            }
+        if drain_queue || !pending.is_empty() {
+            continue;
+        }
+
+        // wait for the next poll, sending outgoing messages right away
+        match &mut outgoing {
+            Some(outgoing) => match time::timeout(POLL_INTERVAL, outgoing.recv()).await {
+                Ok(Some(msg)) => enqueue(&mut pending, &mut own, msg),
+                Ok(None) => return Ok(()),
+                Err(_) => (),
+            },
+            None => time::sleep(POLL_INTERVAL).await,
+        }
+    }
+}
+
+/// Extracts the entries of a room from a relay's reply, advancing `cursor` past them, and returns the messages they complete and the progress of incomplete ones.
+///
+/// Entries seen before and parts of messages whose id is found in `own` are skipped.
+fn room_messages(
+    reply: DNSMessage,
+    cursor: &mut u32,
+    own: &VecDeque<u32>,
+    codec: &Codec,
+    assembler: &mut Assembler,
+) -> Vec<Update> {
+    let mut updates = Vec::new();
+    for answer in reply.answers.unwrap_or_default() {
+        let (number, part) = match relay::decode_entry(&answer.record) {
+            Some((number, payload)) => match codec.decode_payload(&payload) {
+                Some(part) => (number, part),
+                None => continue,
+            },
+            None => continue,
+        };
+        if number < *cursor {
+            continue;
+        }
+        *cursor = number + 1;
+        if own.contains(&part.id) {
+            continue;
+        }
+        updates.extend(assembler.add(part));
+    }
+    updates
+}
+
+/// A query carrying upstream data.
+struct Upload {
+    query: DNSMessage,
+    /// Id of the message the data belongs to
+    id: u32,
+    /// What to report once the query was answered, i.e. progress after the last query of a part and delivery after the last one of the message
+    answered: Vec<Update>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::network::Udp;
+    use crate::transport::Handler;
+    use async_trait::async_trait;
+    use std::io;
+    use tokio::sync::mpsc;
+
+    /// A peer never replying, like one behind a resolver dropping malformed replies.
+    struct Silent;
+
+    #[async_trait]
+    impl Transport for Silent {
+        async fn query(&self, _request: &DNSMessage) -> io::Result<Vec<DNSMessage>> {
+            Ok(Vec::new())
         }
 
-        match stream.shutdown(Shutdown::Both) {
-            Ok(()) => (),
-            Err(e) if e.kind() == io::ErrorKind::NotConnected => (),
-            Err(e) => panic!("{}", e),
+        async fn serve(&self, _handler: Arc<dyn Handler>) -> io::Result<()> {
+            Ok(())
         }
+    }
 
-        thread::sleep(Duration::from_secs(5));
+    #[tokio::test]
+    async fn query_errors_are_reported() {
+        // without a target, every query fails
+        let transport = Arc::new(Udp::new(0));
+        let (sx, mut updates) = mpsc::unbounded_channel();
+        let polling = tokio::spawn(poll_messages(
+            sx,
+            transport,
+            "chat.example".into(),
+            None,
+            None,
+            Codec::default(),
+        ));
+
+        let update = updates.recv().await.unwrap();
+        assert!(matches!(update, Update::Activity(Activity::Unreachable(_))));
+        // polling goes on
+        assert!(!polling.is_finished());
+        polling.abort();
+    }
+
+    #[tokio::test]
+    async fn uploads_without_reply_are_not_delivered() {
+        let (outgoing, rx) = mpsc::unbounded_channel();
+        let (sx, mut updates) = mpsc::unbounded_channel();
+        let msg = ChatMessage::new("hello".into());
+        outgoing.send(msg.clone()).unwrap();
+        let polling = tokio::spawn(poll_messages(
+            sx,
+            Arc::new(Silent),
+            "chat.example".into(),
+            None,
+            Some(rx),
+            Codec::default(),
+        ));
+
+        let mut undelivered = false;
+        while !undelivered {
+            match updates.recv().await.unwrap() {
+                Update::Undelivered(id) => undelivered = id == msg.id,
+                Update::Delivered(_) => panic!("delivered without a reply"),
+                _ => (),
+            }
+        }
+        polling.abort();
     }
 }
@@ -0,0 +1,339 @@
+//! Reliable delivery of the messages a peer polls.
+//!
+//! Every message sent in a reply is numbered and preceded by a character-string of the form `<session>:<seq>`, where the session identifies the run of the sending peer. Encrypted messages are bound to it, so they cannot be replayed under another number.
+//! The polling peer acknowledges everything it received in order by polling `<next>.<session>.ack.<zone>`, `next` being the first sequence number it is still missing.
+//! Until then, the message is sent again in reply to every query, so that neither failed writes nor lost replies lose it. Duplicates are suppressed by the polling peer, which delivers messages in order only.
+
+use super::parts::{self, Part};
+use super::{ChatMessage, Codec, Progress, Update};
+use crate::dns::messages::DNSMessage;
+use crate::dns::types::RecordData;
+use std::collections::{BTreeMap, VecDeque};
+
+/// The label separating acknowledgements from the zone.
+const ACK_LABEL: &str = "ack";
+
+/// Maximum length of the character-string holding session and sequence number, including its length byte.
+const HEADER_LENGTH: usize = 22;
+
+/// Maximum number of bytes acknowledging labels add to a query name, i.e. `<next>.<session>.ack.`.
+const MAX_ACK_LENGTH: usize = 26;
+
+/// Number of sequence numbers ahead of the next expected one that are buffered when arriving early.
+const RECEIVE_WINDOW: u32 = 1024;
+
+/// Messages waiting to be polled, kept until they are acknowledged.
+pub struct Outbox {
+    /// Converts messages into their representation on the wire
+    codec: Codec,
+    /// Identifies this run of the peer, so that polling peers notice restarts
+    session: u32,
+    /// Sequence number of the next message to be sent
+    next_seq: u32,
+    /// Messages not sent yet, which are only split into parts once the size of the replies is known
+    queued: VecDeque<ChatMessage>,
+    /// Parts sent, but not acknowledged yet, oldest first
+    in_flight: VecDeque<InFlight>,
+}
+
+/// A numbered part sent, but not acknowledged yet.
+struct InFlight {
+    seq: u32,
+    part: Part,
+    /// The entry carrying the part, encoded once it is first sent
+    entry: Option<RecordData>,
+}
+
+impl Outbox {
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            session: rand::random(),
+            next_seq: 0,
+            queued: VecDeque::new(),
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    /// Computes how many bytes of message data fit into a numbered entry of `reply` (see `Codec::data_capacity`).
+    ///
+    /// Parts stay the same size until they are acknowledged, so room is left for the question of later replies growing by an acknowledgement.
+    pub fn capacity(&self, reply: &DNSMessage, size_limit: usize) -> usize {
+        self.codec
+            .data_capacity(reply, size_limit, HEADER_LENGTH + MAX_ACK_LENGTH)
+    }
+
+    /// The number of messages queued and parts sent, but not acknowledged yet.
+    pub fn pending(&self) -> usize {
+        self.queued.len() + self.in_flight.len()
+    }
+
+    /// Queues a message for sending.
+    pub fn push(&mut self, msg: ChatMessage) {
+        self.queued.push_back(msg);
+    }
+
+    /// Drops all messages acknowledged by the query name of `request`, returning the progress of messages split into several parts and the ids of messages delivered completely.
+    pub fn acknowledge(&mut self, request: &DNSMessage, zone: &str) -> Vec<Update> {
+        let mut updates = Vec::new();
+        let (session, next) = match request
+            .questions
+            .first()
+            .and_then(|question| parse_ack(&question.name, zone))
+        {
+            Some(ack) => ack,
+            None => return updates,
+        };
+
+        if session == self.session {
+            while matches!(self.in_flight.front(), Some(sent) if sent.seq < next) {
+                let part = self.in_flight.pop_front().unwrap().part;
+                if part.count > 1 {
+                    updates.push(Update::Progress(Progress {
+                        id: part.id,
+                        outgoing: true,
+                        done: part.index + 1,
+                        total: part.count,
+                    }));
+                }
+                // parts are acknowledged in order, so the message is complete with its last one
+                if part.index + 1 == part.count {
+                    updates.push(Update::Delivered(part.id));
+                }
+            }
+        }
+        updates
+    }
+
+    /// The entries carrying all messages not acknowledged yet, oldest first.
+    ///
+    /// Queued messages are split into parts carrying at most `capacity` bytes of data each, which are numbered. Entries are encoded as they are taken from the iterator and kept until acknowledged, so sending them again is cheap.
+    pub fn entries(&mut self, capacity: usize) -> impl Iterator<Item = &RecordData> {
+        let queued: Vec<ChatMessage> = self.queued.drain(..).collect();
+        for part in queued
+            .into_iter()
+            .flat_map(|msg| parts::split(&msg, capacity))
+        {
+            self.number(part);
+        }
+
+        let (codec, session) = (&self.codec, self.session);
+        self.in_flight.iter_mut().map(move |sent| {
+            let InFlight { seq, part, entry } = sent;
+            &*entry.get_or_insert_with(|| encode_entry(codec, session, *seq, part))
+        })
+    }
+
+    /// Assigns the next sequence number to `part`, which counts as sent from now on.
+    fn number(&mut self, part: Part) {
+        self.in_flight.push_back(InFlight {
+            seq: self.next_seq,
+            part,
+            entry: None,
+        });
+        self.next_seq = self.next_seq.wrapping_add(1);
+    }
+}
+
+/// Encodes a part as TXT data, preceded by session and sequence number.
+fn encode_entry(codec: &Codec, session: u32, seq: u32, part: &Part) -> RecordData {
+    let header = format!("{}:{}", session, seq).into_bytes();
+    let mut strings = codec.encode_txt(part, &header);
+    strings.insert(0, header);
+    RecordData::Txt(strings)
+}
+
+/// Parts of messages received from a peer, delivered in order and without duplicates.
+pub struct Inbox {
+    /// Converts messages from their representation on the wire
+    codec: Codec,
+    /// Session of the peer, `None` until the first message arrives
+    session: Option<u32>,
+    /// Sequence number of the next message to be delivered
+    next: u32,
+    /// Parts that arrived ahead of others
+    early: BTreeMap<u32, Part>,
+}
+
+impl Inbox {
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            session: None,
+            next: 0,
+            early: BTreeMap::new(),
+        }
+    }
+
+    /// The name to poll below `zone`, acknowledging all messages delivered so far.
+    pub fn poll_name(&self, zone: &str) -> String {
+        match self.session {
+            Some(session) => format!("{}.{}.{}.{}", self.next, session, ACK_LABEL, zone),
+            None => zone.to_string(),
+        }
+    }
+
+    /// Accepts a numbered entry, returning all parts that can be delivered in order now.
+    pub fn receive(&mut self, record: &RecordData) -> Vec<Part> {
+        let (session, seq, part) = match self.decode_entry(record) {
+            Some(entry) => entry,
+            None => return Vec::new(),
+        };
+
+        // the peer restarted or this is the first contact, so resume with the oldest message it still has
+        if self.session != Some(session) {
+            self.session = Some(session);
+            self.next = seq;
+            self.early.clear();
+        }
+
+        if seq.wrapping_sub(self.next) < RECEIVE_WINDOW {
+            self.early.insert(seq, part);
+        }
+
+        let mut delivered = Vec::new();
+        while let Some(part) = self.early.remove(&self.next) {
+            delivered.push(part);
+            self.next = self.next.wrapping_add(1);
+        }
+        delivered
+    }
+
+    /// Decodes a part along with its session and sequence number from TXT data.
+    fn decode_entry(&self, record: &RecordData) -> Option<(u32, u32, Part)> {
+        match record {
+            RecordData::Txt(strings) => {
+                let (header, strings) = strings.split_first()?;
+                let (session, seq) = std::str::from_utf8(header).ok()?.split_once(':')?;
+                Some((
+                    session.parse().ok()?,
+                    seq.parse().ok()?,
+                    self.codec.decode_txt(strings, header)?,
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Extracts session and next expected sequence number from an acknowledging query name below `zone`.
+fn parse_ack(name: &str, zone: &str) -> Option<(u32, u32)> {
+    // resolvers may alter the case of query names
+    let name = name.to_ascii_lowercase();
+    let suffix = format!(".{}.{}", ACK_LABEL, zone.to_ascii_lowercase());
+    let mut labels = name.strip_suffix(&suffix)?.split('.');
+
+    let next = labels.next()?.parse().ok()?;
+    let session = labels.next()?.parse().ok()?;
+    if labels.next().is_some() {
+        return None;
+    }
+    Some((session, next))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::crypto::{self, Cipher, PublicKey};
+
+    fn message(text: &str) -> ChatMessage {
+        ChatMessage::new(text.into())
+    }
+
+    fn all_entries(outbox: &mut Outbox, capacity: usize) -> Vec<RecordData> {
+        outbox.entries(capacity).cloned().collect()
+    }
+
+    /// The data carried by `parts`, i.e. the pieces of the encoded messages.
+    fn texts(parts: Vec<Part>) -> Vec<String> {
+        parts
+            .into_iter()
+            .map(|part| String::from_utf8(part.data).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn messages_are_sent_until_acknowledged() {
+        let mut outbox = Outbox::new(Codec::default());
+        let mut inbox = Inbox::new(Codec::default());
+        outbox.push(message("first"));
+        outbox.push(message("second"));
+
+        // the reply gets lost, so the messages are sent again
+        let lost = all_entries(&mut outbox, 1000);
+        let entries = all_entries(&mut outbox, 1000);
+        assert_eq!(lost, entries);
+        assert_eq!(texts(inbox.receive(&entries[0])), vec!["T\0first"]);
+        // a duplicate is not delivered twice
+        assert!(inbox.receive(&entries[0]).is_empty());
+
+        let poll = DNSMessage::new_request(1, inbox.poll_name("chat.example").to_uppercase());
+        let delivered = outbox.acknowledge(&poll, "chat.example");
+        assert!(matches!(&delivered[..], [Update::Delivered(_)]));
+        assert_eq!(all_entries(&mut outbox, 1000), &entries[1..]);
+        assert_eq!(texts(inbox.receive(&entries[1])), vec!["T\0second"]);
+
+        let poll = DNSMessage::new_request(1, inbox.poll_name("chat.example"));
+        outbox.acknowledge(&poll, "chat.example");
+        assert!(all_entries(&mut outbox, 1000).is_empty());
+    }
+
+    #[test]
+    fn messages_are_delivered_in_order() {
+        let mut outbox = Outbox::new(Codec::default());
+        let mut inbox = Inbox::new(Codec::default());
+        outbox.push(message("abcdefgh"));
+        outbox.push(message("ij"));
+
+        let entries = all_entries(&mut outbox, 5);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(texts(inbox.receive(&entries[0])), vec!["T\0abc"]);
+        assert!(inbox.receive(&entries[2]).is_empty());
+        assert_eq!(texts(inbox.receive(&entries[1])), vec!["defgh", "T\0ij"]);
+
+        // everything is sent again until acknowledged
+        assert_eq!(all_entries(&mut outbox, 5), entries);
+    }
+
+    #[test]
+    fn restarts_are_detected() {
+        let mut inbox = Inbox::new(Codec::default());
+        for _ in 0..2 {
+            let mut outbox = Outbox::new(Codec::default());
+            outbox.push(message("hello"));
+            let entries = all_entries(&mut outbox, 1000);
+            assert_eq!(texts(inbox.receive(&entries[0])), vec!["T\0hello"]);
+        }
+    }
+
+    #[test]
+    fn acknowledgements_of_other_sessions_are_ignored() {
+        let mut outbox = Outbox::new(Codec::default());
+        outbox.push(message("hello"));
+        all_entries(&mut outbox, 1000);
+
+        let name = format!("1.{}.ack.chat.example", outbox.session.wrapping_add(1));
+        outbox.acknowledge(&DNSMessage::new_request(1, name), "chat.example");
+        assert_eq!(all_entries(&mut outbox, 1000).len(), 1);
+    }
+
+    #[test]
+    fn encrypted_entries_cannot_be_renumbered() {
+        let secret = crypto::generate_key();
+        let codec = || Codec::new(Cipher::new(&secret, &PublicKey::from(&secret)));
+        let mut outbox = Outbox::new(codec());
+        let mut inbox = Inbox::new(codec());
+        outbox.push(message("hello"));
+        let entry = all_entries(&mut outbox, 1000).remove(0);
+        // entries are encrypted only once, so they are sent again as they are
+        assert_eq!(all_entries(&mut outbox, 1000), vec![entry.clone()]);
+
+        // replayed under a later sequence number, e.g. after the original was acknowledged
+        let mut replayed = entry.clone();
+        if let RecordData::Txt(strings) = &mut replayed {
+            strings[0] = format!("{}:{}", outbox.session, 1).into_bytes();
+        }
+        assert!(inbox.receive(&replayed).is_empty());
+        assert_eq!(texts(inbox.receive(&entry)), vec!["T\0hello"]);
+    }
+}
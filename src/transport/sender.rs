@@ -1,90 +1,223 @@
+use crate::dns::edns::Edns;
 use crate::dns::messages::DNSMessage;
-use crate::dns::types::RecordData;
-use crate::transport::ChatMessage;
-use std::collections::VecDeque;
-use std::convert::TryFrom;
-use std::io::{self, Read, Write};
-use std::net::{Shutdown, TcpListener};
-use std::sync::mpsc::{Receiver, RecvError};
-
-pub fn run_sender(
-    message_receiver: Receiver<ChatMessage>,
-    listening_port: u16,
-) -> Result<(), RecvError> {
-    let mut buffer: VecDeque<RecordData> = VecDeque::new();
-    // buffer for parsing incoming messages
-    let mut reading_buffer = [0; 65535];
-
-    let listener = TcpListener::bind(("0.0.0.0", listening_port))
-        .expect("Could not bind listener to port. Is something else running?");
-    listener
-        .set_nonblocking(true)
-        .expect("Could not move listener to non-blocking mode.");
-
-    'inner: loop {
-        // buffer as many messages as possible
-        while let Ok(msg) = message_receiver.try_recv() {
-            buffer.push_back(msg.into());
+use crate::transport::parts::{Assembler, Part};
+use crate::transport::reliable::Outbox;
+use crate::transport::upstream::{Fragment, Reassembler};
+use crate::transport::{
+    self, Activity, ChatMessage, Codec, Handler, Medium, Transport, Update, EDNS_PAYLOAD_SIZE,
+};
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+/// State shared by the tasks answering requests.
+struct Mailbox {
+    /// Messages waiting to be polled
+    outbox: Outbox,
+    /// Messages being carried upstream
+    reassembler: Reassembler,
+    /// Parts of messages carried upstream
+    assembler: Assembler,
+    /// Receives the messages carried upstream, the progress and delivery of messages and the activity of peers
+    message_sender: UnboundedSender<Update>,
+    zone: String,
+    codec: Codec,
+}
+
+impl Handler for Mutex<Mailbox> {
+    fn handle(&self, request: &DNSMessage, medium: Medium) -> Vec<DNSMessage> {
+        let mut mailbox = self.lock().unwrap();
+        let Mailbox {
+            outbox,
+            reassembler,
+            assembler,
+            message_sender,
+            zone,
+            codec,
+        } = &mut *mailbox;
+
+        let part = receive_upstream(request, zone, reassembler, codec);
+        let mut updates: Vec<Update> = part
+            .and_then(|part| assembler.add(part))
+            .into_iter()
+            .collect();
+        updates.extend(outbox.acknowledge(request, zone));
+
+        let replies = match medium {
+            Medium::Datagram => vec![udp_reply(request, outbox)],
+            Medium::Stream => tcp_replies(request, outbox),
+        };
+
+        let traffic = Activity::Traffic {
+            sent: replies
+                .iter()
+                .map(|reply| Vec::<u8>::from(reply.clone()).len())
+                .sum(),
+            received: Vec::<u8>::from(request.clone()).len(),
+        };
+        updates.extend(
+            vec![
+                Activity::Queried,
+                traffic,
+                Activity::Queue(outbox.pending()),
+            ]
+            .into_iter()
+            .map(Update::Activity),
+        );
+        for update in updates {
+            // the sender is shutting down if nobody receives messages anymore
+            let _ = message_sender.send(update);
         }
 
-        // see if a message request arrived
-        match listener.accept() {
-            Ok((mut socket, _remote_addr)) => {
-                // answer the request if any data is available
-                socket.set_nonblocking(false).unwrap();
-
-                // read the request from the socket into a buffer & parse it
-                let read_length = match socket.read(&mut reading_buffer) {
-                    Ok(s) => s,
-                    Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => continue 'inner,
-                    Err(ref e) => panic!("{:?}", e),
-                };
-
-                if !buffer.is_empty() {
-                    let packet_len =
-                        u16::from_be_bytes([reading_buffer[0], reading_buffer[1]]) as usize;
-
-                    if read_length != packet_len + 2 {
-                        eprintln!(
-                            "[sender] Received {} bytes, but packet reports {} bytes!",
-                            read_length,
-                            packet_len + 2
-                        );
-                    }
-
-                    // NOTE(feliix42): RFC 1035, 4.2.2 - TCP usage requires prepending the message with 2
-                    // bytes length information that does not include said two bytes
-                    let parsed = DNSMessage::from(&reading_buffer[2..packet_len + 2]);
-
-                    for msg in buffer.drain(..) {
-                        // translate each message in a DNS reply & send it:
-                        // - clone the received message, add reply
-                        let mut reply = parsed.clone();
-                        reply.add_answer(msg);
-                        let mut sendable: Vec<u8> = reply.into();
-
-                        // prepend the length of the message for TCP transfer
-                        let len = u16::try_from(sendable.len()).unwrap();
-                        let split = len.to_be_bytes();
-                        sendable.insert(0, split[1]);
-                        sendable.insert(0, split[0]);
-
-                        // TODO(feliix42): Error handling
-                        // - then send
-                        socket.write(&sendable).unwrap();
-                        socket.flush().unwrap();
-                    }
-                }
+        replies
+    }
+}
+
+/// Answers the queries of peers arriving via `transport` with the messages queued for sending.
+///
+/// Messages are sent again until the peer acknowledges them (see `transport::reliable`).
+///
+/// Messages that peers carry upstream in their query names (see `transport::upstream`) are passed on to `message_sender`, as is the progress of messages split into several parts, the delivery of messages and every query answered.
+/// Returns once `message_receiver` is closed, or with the error that made serving requests fail, e.g. because the port is taken.
+pub async fn run_sender(
+    mut message_receiver: UnboundedReceiver<ChatMessage>,
+    message_sender: UnboundedSender<Update>,
+    transport: Arc<dyn Transport>,
+    zone: String,
+    codec: Codec,
+) -> io::Result<()> {
+    let mailbox = Arc::new(Mutex::new(Mailbox {
+        outbox: Outbox::new(codec.clone()),
+        reassembler: Reassembler::new(),
+        assembler: Assembler::new(),
+        message_sender,
+        zone,
+        codec,
+    }));
 
-                // clear the buffer
-                for i in 0..read_length {
-                    // please rustc, vectorize this
-                    reading_buffer[i] = 0;
+    let serving = transport.serve(mailbox.clone());
+    tokio::pin!(serving);
+
+    loop {
+        tokio::select! {
+            msg = message_receiver.recv() => match msg {
+                Some(msg) => {
+                    let mut mailbox = mailbox.lock().unwrap();
+                    mailbox.outbox.push(msg);
+                    let queue = Activity::Queue(mailbox.outbox.pending());
+                    let _ = mailbox.message_sender.send(Update::Activity(queue));
                 }
-                socket.shutdown(Shutdown::Both).unwrap();
+                None => return Ok(()),
+            },
+
+            served = &mut serving => return served,
+        }
+    }
+}
+
+/// Extracts upstream data from the query name of `request`, returning the part of a message it carries once it is complete.
+fn receive_upstream(
+    request: &DNSMessage,
+    zone: &str,
+    reassembler: &mut Reassembler,
+    codec: &Codec,
+) -> Option<Part> {
+    let question = request.questions.first()?;
+    let fragment = Fragment::from_name(&question.name, zone)?;
+    codec.decode_payload(&reassembler.add(fragment)?)
+}
+
+/// Builds the reply to a request received via UDP, containing as many unacknowledged messages as fit.
+///
+/// Queued messages exceeding the negotiated payload size are split. If the oldest message still does not fit, e.g. because it was numbered for a larger reply before, the reply is sent empty with the TC bit set, which makes the requester retry via TCP.
+fn udp_reply(request: &DNSMessage, outbox: &mut Outbox) -> DNSMessage {
+    let size_limit = Edns::negotiate(request.edns().as_ref(), EDNS_PAYLOAD_SIZE) as usize;
+    let mut template = transport::reply_template(request);
+    let capacity = outbox.capacity(&template, size_limit);
+
+    // the remaining messages are sent with the replies to the next requests
+    match transport::pack_reply(&template, outbox.entries(capacity), size_limit) {
+        Some(reply) => reply,
+        None => {
+            template.header.is_truncated = true;
+            template
+        }
+    }
+}
+
+/// Builds the replies to a request received via TCP, carrying all messages not acknowledged yet.
+fn tcp_replies(request: &DNSMessage, outbox: &mut Outbox) -> Vec<DNSMessage> {
+    // split the messages so that each reply fits into the negotiated payload size
+    let size_limit = reply_size_limit(request);
+    let template = transport::reply_template(request);
+    let capacity = outbox.capacity(&template, size_limit);
+
+    transport::pack_replies(&template, outbox.entries(capacity), size_limit)
+}
+
+/// The maximum size of a reply to `request` sent via TCP.
+///
+/// Requests without EDNS are only limited by the 16 bit length prefix of TCP messages.
+fn reply_size_limit(request: &DNSMessage) -> usize {
+    match request.edns() {
+        Some(edns) => Edns::negotiate(Some(&edns), EDNS_PAYLOAD_SIZE) as usize,
+        None => u16::MAX as usize,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::reliable::Inbox;
+
+    #[test]
+    fn replies_echo_the_query() {
+        let request = DNSMessage::new_request(rand::random(), "ifsr.de".into());
+        let mut outbox = Outbox::new(Codec::default());
+        outbox.push(ChatMessage::new("hello".into()));
+
+        assert!(udp_reply(&request, &mut outbox).is_reply_to(&request));
+    }
+
+    #[test]
+    fn udp_reply_fits_into_512_bytes() {
+        let mut outbox = Outbox::new(Codec::default());
+        let mut inbox = Inbox::new(Codec::default());
+        let mut assembler = Assembler::new();
+        outbox.push(ChatMessage::new("ä".repeat(1000)));
+
+        // the message is split into parts, which are sent with the replies to the next requests
+        let mut received = Vec::new();
+        for _ in 0..10 {
+            let request = DNSMessage::new_request(23481, inbox.poll_name("ifsr.de"));
+            outbox.acknowledge(&request, "ifsr.de");
+            let reply = udp_reply(&request, &mut outbox);
+            assert!(!reply.header.is_truncated);
+            assert!(Vec::<u8>::from(reply.clone()).len() <= 512);
+
+            for answer in reply.answers.unwrap_or_default() {
+                let parts = inbox.receive(&answer.record);
+                received.extend(parts.into_iter().filter_map(|part| assembler.add(part)));
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
-            Err(e) => panic!("encountered IO error: {}", e),
         }
+
+        let (last, progress) = received.split_last().unwrap();
+        assert!(matches!(last, Update::Message(msg) if msg.text == "ä".repeat(1000)));
+        assert!(progress
+            .iter()
+            .all(|update| matches!(update, Update::Progress(_))));
+    }
+
+    #[test]
+    fn queued_messages_share_a_reply() {
+        let request = DNSMessage::new_request(23481, "ifsr.de".into());
+        let mut outbox = Outbox::new(Codec::default());
+        for text in ["one", "two", "three"].iter() {
+            outbox.push(ChatMessage::new(text.to_string()));
+        }
+
+        let reply = udp_reply(&request, &mut outbox);
+        assert_eq!(reply.header.answer_count, 3);
+        assert_eq!(tcp_replies(&request, &mut outbox).len(), 1);
     }
 }
@@ -0,0 +1,317 @@
+//! Carrying chat messages upstream inside query names.
+//!
+//! Peers behind NAT cannot run a listener that others poll. Instead, they encode their messages into the names of the queries they send, which reach the serving peer even through recursive resolvers.
+//! A part of a message (see `transport::parts`) is base32 encoded and spread over as many queries as necessary, each query name having the form
+//!
+//! ```text
+//! <data>.<data>.<...>.<session><id><index><count>.up.<zone>
+//! ```
+//!
+//! where the control label holds the session of the sending peer as 8 digit hex number, followed by the message id, the index of the fragment and the total number of fragments as 4 digit hex numbers each.
+//! The session is picked at random by every peer when it starts, so messages of several peers sending to the same server, or of a peer that restarted, are never mixed up even if their ids are the same.
+
+use super::parts::{Part, MAX_UPSTREAM_LENGTH};
+use super::{base32, Codec};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// The label separating upstream data from the zone.
+const UPSTREAM_LABEL: &str = "up";
+
+/// Maximum length of a domain name in its textual form without a trailing dot.
+const MAX_NAME_LENGTH: usize = 253;
+
+/// Maximum length of a single label.
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// Length of the control label holding session, message id, fragment index and fragment count.
+const CONTROL_LABEL_LENGTH: usize = 20;
+
+/// Incomplete messages are discarded after this time.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Number of completed messages remembered to suppress repeated queries.
+const COMPLETED_HISTORY: usize = 64;
+
+/// Maximum number of incomplete messages kept, the oldest one being discarded for a new one.
+const MAX_PENDING_MESSAGES: usize = 64;
+
+/// Maximum length of a payload carried upstream, leaving room for the envelope in any format and for encryption.
+const MAX_PAYLOAD_LENGTH: usize = MAX_UPSTREAM_LENGTH + 128;
+
+/// Number of base32 characters carried by a single query name below `zone`.
+fn fragment_capacity(zone: &str) -> usize {
+    let suffix_length = CONTROL_LABEL_LENGTH + UPSTREAM_LABEL.len() + zone.len() + 2;
+    // every data label is followed by a dot
+    let available = MAX_NAME_LENGTH.saturating_sub(suffix_length);
+    (available - available.div_ceil(MAX_LABEL_LENGTH + 1)).max(1)
+}
+
+/// Maximum number of fragments a payload carried upstream below `zone` may need.
+fn max_fragments(zone: &str) -> usize {
+    // every base32 character carries 5 bits
+    (MAX_PAYLOAD_LENGTH * 8)
+        .div_ceil(5)
+        .div_ceil(fragment_capacity(zone))
+}
+
+/// Encodes `part` into the names of the queries carrying it to the peer, as message `id` of `session`.
+pub fn encode_message(
+    part: &Part,
+    session: u32,
+    id: u16,
+    zone: &str,
+    codec: &Codec,
+) -> Vec<String> {
+    let encoded = base32::encode(&codec.encode_payload(part));
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(fragment_capacity(zone)).collect();
+    let count = chunks.len() as u16;
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut labels: Vec<&str> = chunk
+                .chunks(MAX_LABEL_LENGTH)
+                .map(|label| std::str::from_utf8(label).unwrap())
+                .collect();
+            let control = format!("{:08x}{:04x}{:04x}{:04x}", session, id, index, count);
+            labels.push(&control);
+            labels.push(UPSTREAM_LABEL);
+            labels.push(zone);
+            labels.join(".")
+        })
+        .collect()
+}
+
+/// A piece of upstream data extracted from a query name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fragment {
+    /// Identifies the run of the peer sending the message
+    pub session: u32,
+    /// Identifies the message the fragment belongs to within the session
+    pub id: u16,
+    /// Position of the fragment within the message
+    pub index: u16,
+    /// Number of fragments making up the message
+    pub count: u16,
+    /// Base32 encoded data carried by the fragment
+    pub data: String,
+}
+
+impl Fragment {
+    /// Extracts the upstream data from a query name below `zone`. Returns `None` for names not carrying data, e.g. plain polls, and for messages longer than any part carried upstream.
+    pub fn from_name(name: &str, zone: &str) -> Option<Self> {
+        // resolvers may alter the case of query names
+        let name = name.to_ascii_lowercase();
+        let suffix = format!(".{}.{}", UPSTREAM_LABEL, zone.to_ascii_lowercase());
+        let mut labels: Vec<&str> = name.strip_suffix(&suffix)?.split('.').collect();
+
+        let control = labels.pop()?;
+        if control.len() != CONTROL_LABEL_LENGTH || !control.is_ascii() {
+            return None;
+        }
+        let session = u32::from_str_radix(&control[0..8], 16).ok()?;
+        let id = u16::from_str_radix(&control[8..12], 16).ok()?;
+        let index = u16::from_str_radix(&control[12..16], 16).ok()?;
+        let count = u16::from_str_radix(&control[16..20], 16).ok()?;
+        if index >= count || count as usize > max_fragments(zone) {
+            return None;
+        }
+
+        Some(Self {
+            session,
+            id,
+            index,
+            count,
+            data: labels.concat(),
+        })
+    }
+}
+
+/// Identifies a message by the session of its sender and its id.
+type MessageKey = (u32, u16);
+
+/// Collects fragments until the messages they belong to are complete.
+#[derive(Default)]
+pub struct Reassembler {
+    /// Fragments of incomplete messages, along with the time the first one arrived
+    pending: HashMap<MessageKey, (Instant, Vec<Option<String>>)>,
+    /// Recently completed messages
+    completed: VecDeque<MessageKey>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a fragment, returning the payload of the message once all of its fragments have arrived (see `Codec::decode_payload`).
+    ///
+    /// Fragments arriving more than once, e.g. because a resolver retried a query, are ignored.
+    pub fn add(&mut self, fragment: Fragment) -> Option<Vec<u8>> {
+        self.pending
+            .retain(|_, (started, _)| started.elapsed() < REASSEMBLY_TIMEOUT);
+        let key = (fragment.session, fragment.id);
+        if self.completed.contains(&key) {
+            return None;
+        }
+        if !self.pending.contains_key(&key) && self.pending.len() >= MAX_PENDING_MESSAGES {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, (started, _))| *started)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                eprintln!(
+                    "[transport] Too many incomplete messages, discarding {:08x}:{:04x}",
+                    oldest.0, oldest.1
+                );
+                self.pending.remove(&oldest);
+            }
+        }
+
+        let (_, fragments) = self
+            .pending
+            .entry(key)
+            .or_insert_with(|| (Instant::now(), vec![None; fragment.count as usize]));
+        if fragments.len() != fragment.count as usize {
+            // not matching the fragments seen so far, so this is garbage
+            return None;
+        }
+        fragments[fragment.index as usize] = Some(fragment.data);
+        if fragments.iter().any(Option::is_none) {
+            return None;
+        }
+
+        let (_, fragments) = self.pending.remove(&key)?;
+        self.completed.push_back(key);
+        if self.completed.len() > COMPLETED_HISTORY {
+            self.completed.pop_front();
+        }
+
+        let encoded: String = fragments.into_iter().flatten().collect();
+        base32::decode(&encoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{parts, ChatMessage};
+
+    fn part(text: String) -> Part {
+        parts::split(&ChatMessage::new(text), usize::MAX).remove(0)
+    }
+
+    #[test]
+    fn names_are_valid() {
+        let names = encode_message(
+            &part("a".repeat(1000)),
+            7,
+            42,
+            "chat.example.com",
+            &Codec::default(),
+        );
+        assert!(names.len() > 1);
+        for name in names {
+            assert!(name.len() <= MAX_NAME_LENGTH);
+            assert!(name.split('.').all(|label| label.len() <= MAX_LABEL_LENGTH));
+            assert!(name.ends_with(".up.chat.example.com"));
+        }
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let part = part("ünïcödé ".repeat(100));
+
+        let codec = Codec::default();
+        let mut names = encode_message(&part, 7, 42, "example.com", &codec);
+        // arrival order does not matter and resolvers may change the case
+        names.reverse();
+        let count = names.len();
+
+        let mut reassembler = Reassembler::new();
+        for (i, name) in names.iter().enumerate() {
+            let fragment = Fragment::from_name(&name.to_uppercase(), "example.com").unwrap();
+            let result = reassembler.add(fragment);
+            if i + 1 < count {
+                assert!(result.is_none());
+            } else {
+                let payload = result.unwrap();
+                assert_eq!(codec.decode_payload(&payload).unwrap().data, part.data);
+            }
+        }
+
+        // a repeated query must not deliver the message again
+        let fragment = Fragment::from_name(&names[0], "example.com").unwrap();
+        assert!(reassembler.add(fragment).is_none());
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        let zone = "example.com";
+        let control = format!("{:08x}{:04x}{:04x}{:04x}", 7, 1, 0, max_fragments(zone) + 1);
+        let name = format!("abc.{}.up.{}", control, zone);
+        assert_eq!(Fragment::from_name(&name, zone), None);
+
+        // the largest parts carried upstream fit
+        let names = encode_message(
+            &part("a".repeat(MAX_UPSTREAM_LENGTH)),
+            7,
+            1,
+            zone,
+            &Codec::default(),
+        );
+        assert!(names.len() <= max_fragments(zone));
+        assert!(Fragment::from_name(&names[0], zone).is_some());
+    }
+
+    #[test]
+    fn sessions_are_kept_apart() {
+        let codec = Codec::default();
+        let first = part("first!".repeat(100));
+        let second = part("second".repeat(100));
+        // two peers starting with the same message id, their queries arriving interleaved
+        let first_names = encode_message(&first, 1, 0, "example.com", &codec);
+        let second_names = encode_message(&second, 2, 0, "example.com", &codec);
+        assert_eq!(first_names.len(), second_names.len());
+
+        let mut reassembler = Reassembler::new();
+        let mut payloads = Vec::new();
+        for (a, b) in first_names.iter().zip(second_names.iter()) {
+            for name in [a, b].iter() {
+                let fragment = Fragment::from_name(name, "example.com").unwrap();
+                payloads.extend(reassembler.add(fragment));
+            }
+        }
+
+        let data: Vec<Vec<u8>> = payloads
+            .iter()
+            .map(|payload| codec.decode_payload(payload).unwrap().data)
+            .collect();
+        assert_eq!(data, vec![first.data, second.data]);
+    }
+
+    #[test]
+    fn pending_messages_are_limited() {
+        let mut reassembler = Reassembler::new();
+        for id in 0..MAX_PENDING_MESSAGES as u16 * 2 {
+            let fragment = Fragment {
+                session: 7,
+                id,
+                index: 0,
+                count: 2,
+                data: "abc".into(),
+            };
+            assert!(reassembler.add(fragment).is_none());
+        }
+        assert_eq!(reassembler.pending.len(), MAX_PENDING_MESSAGES);
+    }
+
+    #[test]
+    fn plain_polls_carry_no_data() {
+        assert_eq!(Fragment::from_name("example.com", "example.com"), None);
+        assert_eq!(Fragment::from_name("up.example.com", "example.com"), None);
+    }
+}
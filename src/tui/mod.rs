@@ -1,16 +1,20 @@
-use crate::state::{MoveDirection, State};
-use crate::transport::ChatMessage;
+use crate::hub::Hub;
+use crate::state::{Action, MoveDirection, State};
+use crate::transport::Transport;
 use crate::tui::render::Render;
 use crossterm::{
-    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    event::{
+        DisableMouseCapture, EnableMouseCapture, Event, EventStream, KeyCode, KeyEvent,
+        KeyModifiers, MouseEvent, MouseEventKind,
+    },
     terminal, ExecutableCommand,
 };
 use std::{
     io::{self, Write},
     iter::FromIterator,
-    sync::mpsc::{Receiver, Sender},
-    time::Duration,
+    sync::Arc,
 };
+use tokio_stream::StreamExt;
 use tui::{
     backend::CrosstermBackend,
     layout::{Alignment, Constraint, Direction, Layout, Rect},
@@ -23,6 +27,9 @@ use tui::{
 
 mod render;
 
+/// Number of lines scrolled per step of the mouse wheel.
+const SCROLL_LINES: usize = 3;
+
 /// An enclosing structure for the terminal backend for easy setup & teardown.
 pub struct Renderer<W: Write> {
     /// The terminal backend
@@ -32,14 +39,15 @@ pub struct Renderer<W: Write> {
 impl<W: Write> Renderer<W> {
     pub fn new(mut out: W) -> Result<Renderer<W>, crossterm::ErrorKind> {
         terminal::enable_raw_mode()?;
-        out.execute(terminal::EnterAlternateScreen)?;
+        out.execute(terminal::EnterAlternateScreen)?
+            .execute(EnableMouseCapture)?;
 
         Ok(Renderer {
             terminal: Terminal::new(CrosstermBackend::new(out))?,
         })
     }
 
-    pub fn render(&mut self, state: &State) -> Result<(), crossterm::ErrorKind> {
+    pub fn render(&mut self, state: &mut State) -> Result<(), crossterm::ErrorKind> {
         self.terminal
             .draw(|frame| draw(frame, state, frame.size()))?;
         Ok(())
@@ -50,43 +58,96 @@ impl<W: Write> Drop for Renderer<W> {
     fn drop(&mut self) {
         self.terminal
             .backend_mut()
-            .execute(terminal::LeaveAlternateScreen)
+            .execute(DisableMouseCapture)
+            .and_then(|backend| backend.execute(terminal::LeaveAlternateScreen))
             .expect("Could not execute to stdout");
         terminal::disable_raw_mode().expect("Terminal doesn't support to disable raw mode");
     }
 }
 
-fn draw<W: Write>(frame: &mut Frame<'_, CrosstermBackend<W>>, state: &State, size: Rect) {
+/// Draws the user interface, recording the layout of the message pane in `state` for scrolling.
+fn draw<W: Write>(frame: &mut Frame<'_, CrosstermBackend<W>>, state: &mut State, size: Rect) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .margin(1)
-        .constraints([Constraint::Min(0), Constraint::Length(6)].as_ref())
+        .constraints(
+            [
+                Constraint::Min(0),
+                Constraint::Length(6),
+                Constraint::Length(1),
+            ]
+            .as_ref(),
+        )
         .split(size);
 
-    let block = Block::default().title("Messages").borders(Borders::ALL);
-    let messages: Vec<Spans> = state
+    let mut title = String::from("Messages");
+    for progress in state.transfers.values() {
+        let direction = if progress.outgoing {
+            "sending"
+        } else {
+            "receiving"
+        };
+        title.push_str(&format!(
+            " [{} {}/{}]",
+            direction, progress.done, progress.total
+        ));
+    }
+    if state.unseen > 0 {
+        title.push_str(&format!(" [{} new messages below]", state.unseen));
+    }
+    let block = Block::default().title(title).borders(Borders::ALL);
+
+    let inner = block.inner(chunks[0]);
+    let lines: Vec<Spans> = state
         .messages
         .clone()
         .into_iter()
-        .map(|msg| msg.render())
+        .flat_map(|(msg, ty)| {
+            let line = (msg, ty).render();
+            render::wrap(line, inner.width as usize)
+        })
+        .collect();
+    state.pane = (inner.height as usize, lines.len());
+    let bottom = lines.len().saturating_sub(inner.height as usize);
+    let top = state.scroll.map_or(bottom, |top| top.min(bottom));
+
+    let visible: Vec<Spans> = lines
+        .into_iter()
+        .skip(top)
+        .take(inner.height as usize)
         .collect();
-    let message_panel = Paragraph::new(messages)
+    let message_panel = Paragraph::new(visible)
         .block(block)
         .style(Style::default().fg(Color::White))
-        .alignment(Alignment::Left)
-        .wrap(Wrap { trim: false });
+        .alignment(Alignment::Left);
 
     frame.render_widget(message_panel, chunks[0]);
 
     let input_panel = Paragraph::new(String::from_iter(state.input.iter()))
-        .block(Block::default().borders(Borders::ALL).title(Span::styled(
-            "Compose",
-            Style::default().add_modifier(Modifier::BOLD),
-        )))
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(Spans::from(vec![
+                    Span::styled("Compose", Style::default().add_modifier(Modifier::BOLD)),
+                    Span::styled(
+                        state
+                            .notice
+                            .as_ref()
+                            .map_or_else(String::new, |notice| format!(" ({})", notice)),
+                        Style::default().fg(Color::Yellow),
+                    ),
+                ])),
+        )
         .style(Style::default().fg(Color::White))
         .alignment(Alignment::Left)
         .wrap(Wrap { trim: false });
     frame.render_widget(input_panel, chunks[1]);
+
+    let status_line = Paragraph::new(state.connection.clone().render())
+        .style(Style::default().fg(Color::White))
+        .alignment(Alignment::Left);
+    frame.render_widget(status_line, chunks[2]);
+
     let inner_width = chunks[1].width - 2;
     frame.set_cursor(
         chunks[1].x + 1 + (state.cursor_pos as u16 % inner_width),
@@ -94,27 +155,33 @@ fn draw<W: Write>(frame: &mut Frame<'_, CrosstermBackend<W>>, state: &State, siz
     );
 }
 
-pub fn run(
-    sender: Sender<ChatMessage>,
-    recv: Receiver<ChatMessage>,
+/// Runs the user interface on the conversation of `hub` until the user quits, redrawing whenever the conversation changes or a key is pressed.
+///
+/// `/connect` changes the target of `polling`, the transport of the poller, if there is one.
+pub async fn run(
+    hub: Arc<Hub>,
+    polling: Option<Arc<dyn Transport>>,
 ) -> Result<(), crossterm::ErrorKind> {
-    let mut state = State::new();
-
     let stdout = io::stdout();
     let mut renderer = Renderer::new(stdout)?;
+    let mut events = EventStream::new();
+    let mut changes = hub.subscribe();
+    renderer.render(&mut hub.state())?;
 
     'main: loop {
-        // check for input from the Message receiver
-        if let Ok(msg) = recv.try_recv() {
-            state.add_received(msg);
-        }
+        tokio::select! {
+            // redraw whatever changed
+            change = changes.recv() => if change.is_none() {
+                break 'main;
+            },
 
-        // and hear from terminal input queue
-        if event::poll(Duration::from_millis(50))? {
-            match event::read()? {
-                Event::Mouse(_) => (),
-                Event::Resize(_, _) => (),
-                Event::Key(KeyEvent { code, modifiers }) => match code {
+            // and hear from terminal input queue
+            event = events.next() => match event {
+                Some(Ok(Event::Key(KeyEvent { code, modifiers }))) => {
+                let action = {
+                let mut action = Action::None;
+                let mut state = hub.state();
+                match code {
                     KeyCode::Char(character) => {
                         if character == 'c' && modifiers.contains(KeyModifiers::CONTROL) {
                             break 'main;
@@ -122,13 +189,7 @@ pub fn run(
                             state.add_input_char(character);
                         }
                     }
-                    KeyCode::Enter => {
-                        for msg in state.generate_msg() {
-                            sender
-                                .send(msg)
-                                .expect("Message sender thread unavailable!");
-                        }
-                    }
+                    KeyCode::Enter => action = state.submit(),
                     KeyCode::Delete => {
                         state.delete();
                     }
@@ -141,22 +202,62 @@ pub fn run(
                     KeyCode::Right => {
                         state.move_cursor(MoveDirection::Right);
                     }
+                    KeyCode::Home if modifiers.contains(KeyModifiers::CONTROL) => {
+                        state.scroll_to_top();
+                    }
+                    KeyCode::End if modifiers.contains(KeyModifiers::CONTROL) => {
+                        state.scroll_to_bottom();
+                    }
                     KeyCode::Home => {
                         state.move_cursor(MoveDirection::Home);
                     }
                     KeyCode::End => {
                         state.move_cursor(MoveDirection::End);
                     }
+                    KeyCode::PageUp => {
+                        state.page_up();
+                    }
+                    KeyCode::PageDown => {
+                        state.page_down();
+                    }
                     KeyCode::Esc => {
                         break 'main;
                     }
                     _ => (),
+                }
+                action
+                };
+                match action {
+                    Action::None => (),
+                    Action::Send(msg) => {
+                        if let Err(e) = hub.send(msg) {
+                            hub.state().add_system(format!("could not send: {}", e));
+                        }
+                    }
+                    Action::SendFile(path) => {
+                        if let Err(e) = hub.send_file(path.clone()).await {
+                            hub.state().add_system(format!("could not send {}: {}", path.display(), e));
+                        }
+                    }
+                    Action::Connect { host, port } => {
+                        hub.connect(polling.as_deref(), host, port).await
+                    }
+                    Action::Quit => break 'main,
+                }
+                },
+                Some(Ok(Event::Mouse(MouseEvent { kind, .. }))) => match kind {
+                    MouseEventKind::ScrollUp => hub.state().scroll_up(SCROLL_LINES),
+                    MouseEventKind::ScrollDown => hub.state().scroll_down(SCROLL_LINES),
+                    _ => (),
                 },
-            }
+                Some(Ok(Event::Resize(_, _))) => (),
+                Some(Err(e)) => return Err(e),
+                None => break 'main,
+            },
         }
 
         // call the renderer
-        renderer.render(&state)?;
+        renderer.render(&mut hub.state())?;
     }
 
     Ok(())
@@ -1,32 +1,248 @@
-use crate::state::MessageType;
+use crate::state::{self, Connection, MessageType};
 use crate::transport::ChatMessage;
 use tui::{
     style::{Color, Modifier, Style},
     text::{Span, Spans},
 };
+use unicode_width::UnicodeWidthChar;
 
 pub trait Render {
     /// Renders an element to the UI.
     fn render(self) -> Spans<'static>;
 }
 
+/// A message along with its type.
 impl Render for (ChatMessage, MessageType) {
     fn render(self) -> Spans<'static> {
         let (msg, ty) = self;
-        let sender = match ty {
-            MessageType::Sent => Span::styled(
-                "<You> ",
-                Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
-            ),
-            MessageType::Received => Span::styled(
-                "<Them> ",
-                Style::default()
-                    .fg(Color::Green)
-                    .add_modifier(Modifier::BOLD),
-            ),
-        };
         let timestamp = Span::from(msg.sent.time().format("(%H:%M) ").to_string());
+        if ty == MessageType::System {
+            return Spans::from(vec![
+                Span::from("  "),
+                timestamp,
+                Span::styled(
+                    format!("-!- {}", msg.text),
+                    Style::default().fg(Color::Yellow),
+                ),
+            ]);
+        }
+
+        let marker = match ty {
+            MessageType::Queued => Span::styled("… ", Style::default().fg(Color::DarkGray)),
+            MessageType::Delivered => Span::styled("✓ ", Style::default().fg(Color::Green)),
+            MessageType::Failed => Span::styled("✗ ", Style::default().fg(Color::Red)),
+            MessageType::Sent | MessageType::Received | MessageType::System => Span::from("  "),
+        };
+        let name = state::author(&msg, &ty).to_string();
+        let color = match (&msg.nick, ty) {
+            (Some(nick), MessageType::Received) => nick_color(nick),
+            (None, MessageType::Received) => Color::Green,
+            _ => Color::Red,
+        };
+        let style = Style::default().fg(color).add_modifier(Modifier::BOLD);
+
+        let mut spans = vec![marker];
+        if msg.action {
+            spans.push(timestamp);
+            spans.push(Span::styled(format!("* {} ", name), style));
+        } else {
+            spans.push(Span::styled(format!("<{}> ", name), style));
+            spans.push(timestamp);
+        }
+        if let Some(attachment) = msg.attachment {
+            spans.push(Span::styled(
+                format!("[file: {}] ", attachment.name),
+                Style::default().add_modifier(Modifier::ITALIC),
+            ));
+        }
+        spans.push(Span::from(msg.text));
+        Spans::from(spans)
+    }
+}
+
+/// Colors the nicknames of other people are shown in, leaving red for our own.
+const NICK_COLORS: [Color; 10] = [
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGreen,
+    Color::LightYellow,
+    Color::LightBlue,
+    Color::LightMagenta,
+    Color::LightCyan,
+];
+
+/// Picks the color a nickname is shown in, which stays the same across runs and peers.
+fn nick_color(nick: &str) -> Color {
+    // FNV-1a, as the hasher of the standard library is not guaranteed to be stable
+    let hash = nick.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    });
+    NICK_COLORS[hash as usize % NICK_COLORS.len()]
+}
+
+impl Render for Connection {
+    fn render(self) -> Spans<'static> {
+        let mut spans = match (&self.error, self.last_contact) {
+            (Some(error), _) => vec![Span::styled(
+                format!("unreachable: {}", error),
+                Style::default().fg(Color::Red),
+            )],
+            (None, Some(_)) => vec![Span::styled("connected", Style::default().fg(Color::Green))],
+            (None, None) => vec![Span::styled(
+                "waiting for peer",
+                Style::default().fg(Color::Yellow),
+            )],
+        };
+
+        let mut details = Vec::new();
+        if let Some(last_contact) = self.last_contact {
+            details.push(format!(
+                "last contact {}",
+                last_contact.time().format("%H:%M:%S")
+            ));
+        }
+        if let Some(latency) = self.latency {
+            details.push(format!("latency {} ms", latency.as_millis()));
+        }
+        details.push(format!("{} queued", self.queue));
+        details.push(format!(
+            "sent {}, received {}",
+            format_bytes(self.bytes_sent),
+            format_bytes(self.bytes_received)
+        ));
+        for detail in details {
+            spans.push(Span::from(" | "));
+            spans.push(Span::from(detail));
+        }
+
+        Spans::from(spans)
+    }
+}
 
-        Spans::from(vec![sender, timestamp, Span::from(msg.text)])
+/// Formats a number of bytes with a binary prefix.
+fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+/// Breaks `spans` into lines at most `width` columns wide, preferring to break after whitespace.
+///
+/// Wrapping is done here rather than by `Paragraph`, so that the number of lines is known for scrolling.
+pub fn wrap(spans: Spans<'static>, width: usize) -> Vec<Spans<'static>> {
+    let width = width.max(1);
+    let mut lines = Vec::new();
+    // characters of the current line, and where the last word on it starts
+    let mut line: Vec<(char, Style)> = Vec::new();
+    let mut line_width = 0;
+    let mut word_start = None;
+
+    for span in spans.0 {
+        for c in span.content.chars() {
+            if c == '\n' {
+                lines.push(std::mem::take(&mut line));
+                line_width = 0;
+                word_start = None;
+                continue;
+            }
+            let char_width = c.width().unwrap_or(0);
+            if line_width + char_width > width && !line.is_empty() {
+                // move the word being broken to the next line, unless it fills the whole line
+                let rest = match word_start {
+                    Some(start) if !c.is_whitespace() => line.split_off(start),
+                    _ => Vec::new(),
+                };
+                lines.push(std::mem::replace(&mut line, rest));
+                line_width = line.iter().map(|(c, _)| c.width().unwrap_or(0)).sum();
+                word_start = None;
+                if c.is_whitespace() {
+                    continue;
+                }
+            }
+            if c.is_whitespace() {
+                word_start = Some(line.len() + 1);
+            }
+            line.push((c, span.style));
+            line_width += char_width;
+        }
+    }
+    lines.push(line);
+
+    lines
+        .into_iter()
+        .map(|line| {
+            let mut spans: Vec<Span> = Vec::new();
+            for (c, style) in line {
+                match spans.last_mut() {
+                    Some(span) if span.style == style => span.content.to_mut().push(c),
+                    _ => spans.push(Span::styled(c.to_string(), style)),
+                }
+            }
+            Spans::from(spans)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(lines: Vec<Spans>) -> Vec<String> {
+        lines
+            .into_iter()
+            .map(|line| line.0.into_iter().map(|span| span.content).collect())
+            .collect()
+    }
+
+    #[test]
+    fn byte_counts_are_readable() {
+        assert_eq!(format_bytes(1000), "1000 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024), "3.0 MiB");
+    }
+
+    #[test]
+    fn nicknames_keep_their_color() {
+        assert_eq!(nick_color("alice"), nick_color("alice"));
+        let colors: Vec<Color> = ["alice", "bob", "carol", "dave"]
+            .iter()
+            .map(|nick| nick_color(nick))
+            .collect();
+        assert!(colors.iter().any(|color| *color != colors[0]));
+    }
+
+    #[test]
+    fn lines_are_wrapped_at_words() {
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let spans = Spans::from(vec![
+            Span::styled("<You> ", bold),
+            Span::from("hello wonderful\nworld, abcdefghijkl"),
+        ]);
+
+        let lines = wrap(spans, 10);
+        assert_eq!(
+            text(lines.clone()),
+            vec![
+                "<You> ",
+                "hello ",
+                "wonderful",
+                "world, ",
+                "abcdefghij",
+                "kl"
+            ]
+        );
+        assert_eq!(lines[0].0[0].style, bold);
+        assert_eq!(text(wrap(Spans::from("ä€😀😀"), 4)), vec!["ä€😀", "😀"]);
     }
 }